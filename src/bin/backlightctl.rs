@@ -0,0 +1,337 @@
+//! Command line client for backlightd
+use backlightd::{
+    default_socket_path, BacklightCommand, BacklightEvent, BacklightReply, DisplayStatus, Level,
+    PowerState, TargetDisplay, WireFormat,
+};
+use clap::{Parser as ClapParser, Subcommand, ValueEnum};
+use std::{
+    ffi::OsStr,
+    io::{BufRead, BufReader, Read, Write},
+    net::Shutdown,
+    os::unix::net::UnixStream,
+    path::{Path, PathBuf},
+    process::ExitCode,
+};
+
+#[derive(Debug, ClapParser)]
+#[command(about = "Send a command to backlightd and print its reply")]
+struct CtlOptions {
+    /// Path for the server unix socket, defaults to $XDG_RUNTIME_DIR/backlight
+    #[clap(short = 's', long = "socket", env = "BACKLIGHTD_SOCKET_PATH")]
+    socket_path: Option<PathBuf>,
+    /// Talk to the daemon in JSON, and print its replies as JSON
+    #[clap(short = 'j', long = "json")]
+    json: bool,
+    #[command(subcommand)]
+    command: CtlCommand,
+}
+
+/// Mirrors BacklightCommand. Displays are given by name, or "all".
+#[derive(Debug, Subcommand)]
+enum CtlCommand {
+    /// Tell the daemon where to find the sway IPC socket
+    Swaysock { path: PathBuf },
+    /// Turn a display on
+    On { display: TargetDisplay },
+    /// Turn a display off
+    Off { display: TargetDisplay },
+    /// Raise brightness by a number of levels, which may be fractional
+    Up {
+        display: TargetDisplay,
+        #[arg(default_value_t = 1.0)]
+        steps: f32,
+    },
+    /// Lower brightness by a number of levels, which may be fractional
+    Down {
+        display: TargetDisplay,
+        #[arg(default_value_t = 1.0)]
+        steps: f32,
+    },
+    /// Raise brightness by the configured fine step
+    FineUp { display: TargetDisplay },
+    /// Lower brightness by the configured fine step
+    FineDown { display: TargetDisplay },
+    /// Set a brightness level, or a percentage of the reference range like 40%
+    Set {
+        display: TargetDisplay,
+        #[arg(allow_negative_numbers = true)]
+        level: Level,
+    },
+    /// Turn a display on if it is off, or off if it is on
+    Toggle { display: TargetDisplay },
+    /// Set the highest brightness level
+    Max { display: TargetDisplay },
+    /// Set the lowest brightness level
+    Min { display: TargetDisplay },
+    /// Set the configured default brightness level
+    Default { display: TargetDisplay },
+    /// Report the state of a display
+    #[command(alias = "get")]
+    Status {
+        #[arg(default_value = "all")]
+        display: TargetDisplay,
+        #[arg(short, long, value_enum, default_value_t)]
+        format: OutputFormat,
+    },
+    /// Report the state of every display
+    List,
+    /// Re-read the daemon's config file
+    Reload,
+    /// Read brightness back from displays that support it, and follow it
+    Sync { display: TargetDisplay },
+    /// Print the state of the displays, then again whenever one changes
+    Subscribe {
+        #[arg(default_value = "all")]
+        display: TargetDisplay,
+        #[arg(short, long, value_enum, default_value_t)]
+        format: OutputFormat,
+    },
+    /// Apply several commands together, or none of them if one fails,
+    /// e.g. batch "set laptop 2" "set desktop 5"
+    Batch {
+        #[arg(required = true)]
+        commands: Vec<String>,
+    },
+}
+
+impl TryFrom<CtlCommand> for BacklightCommand {
+    type Error = String;
+    fn try_from(value: CtlCommand) -> Result<Self, Self::Error> {
+        let cmd = match value {
+            CtlCommand::Swaysock { path } => BacklightCommand::SwaySock(path),
+            CtlCommand::On { display } => BacklightCommand::On(display),
+            CtlCommand::Off { display } => BacklightCommand::Off(display),
+            CtlCommand::Up { display, steps } => BacklightCommand::Up(display, steps),
+            CtlCommand::Down { display, steps } => BacklightCommand::Down(display, steps),
+            CtlCommand::FineUp { display } => BacklightCommand::FineUp(display),
+            CtlCommand::FineDown { display } => BacklightCommand::FineDown(display),
+            CtlCommand::Set { display, level } => BacklightCommand::Set(display, level),
+            CtlCommand::Toggle { display } => BacklightCommand::Toggle(display),
+            CtlCommand::Max { display } => BacklightCommand::Max(display),
+            CtlCommand::Min { display } => BacklightCommand::Min(display),
+            CtlCommand::Default { display } => BacklightCommand::Default(display),
+            CtlCommand::Status { display, .. } => BacklightCommand::Status(display),
+            CtlCommand::List => BacklightCommand::List,
+            CtlCommand::Reload => BacklightCommand::Reload,
+            CtlCommand::Sync { display } => BacklightCommand::Sync(display),
+            CtlCommand::Subscribe { .. } => BacklightCommand::Subscribe,
+            CtlCommand::Batch { commands } => {
+                let line = commands.join("; ");
+                BacklightCommand::try_from(line.as_bytes()).map_err(|e| e.to_string())?
+            }
+        };
+        Ok(cmd)
+    }
+}
+
+/// How status is printed. The first display reported sets the text, the
+/// percentage and the CSS class, which is its clamp state. The tooltip lists
+/// every display.
+#[derive(Debug, Default, Clone, Copy, PartialEq, ValueEnum)]
+enum OutputFormat {
+    /// The daemon's reply as it is
+    #[default]
+    Text,
+    /// A waybar custom module, one JSON object per line
+    Waybar,
+    /// A percentage per line, for i3blocks in persist mode
+    I3blocks,
+}
+
+impl CtlCommand {
+    fn output_format(&self) -> OutputFormat {
+        match self {
+            CtlCommand::Status { format, .. } | CtlCommand::Subscribe { format, .. } => *format,
+            _ => OutputFormat::Text,
+        }
+    }
+}
+
+/// None for daemons that do not report a percentage
+fn percentage(d: &DisplayStatus) -> Option<u32> {
+    d.percent.map(|p| p.clamp(0.0, 100.0).round() as u32)
+}
+
+fn tooltip_line(d: &DisplayStatus) -> String {
+    let name = d.name.to_string_lossy();
+    let off = if d.power == PowerState::Off {
+        ", off"
+    } else {
+        ""
+    };
+    match d.percent {
+        Some(percent) => format!("{name}: {percent}% (level {}{off})", d.level),
+        None => format!("{name}: level {}{off}", d.level),
+    }
+}
+
+fn render(displays: &[DisplayStatus], output: OutputFormat) -> String {
+    let Some(first) = displays.first() else {
+        return match output {
+            OutputFormat::Waybar => {
+                serde_json::json!({"text": "", "tooltip": "no displays"}).to_string()
+            }
+            _ => String::new(),
+        };
+    };
+    let text = match percentage(first) {
+        Some(percent) => format!("{percent}%"),
+        None => format!("level {}", first.level),
+    };
+    match output {
+        OutputFormat::Waybar => {
+            let tooltip: Vec<String> = displays.iter().map(tooltip_line).collect();
+            serde_json::json!({
+                "text": text,
+                "percentage": percentage(first),
+                "tooltip": tooltip.join("\n"),
+                "class": first.clamp.as_str(),
+            })
+            .to_string()
+        }
+        _ => text,
+    }
+}
+
+fn selects(target: &TargetDisplay, name: &OsStr) -> bool {
+    match target {
+        TargetDisplay::All => true,
+        TargetDisplay::Display(n) => n == name,
+    }
+}
+
+fn send_command(
+    socket_path: &Path,
+    cmd: &BacklightCommand,
+    format: WireFormat,
+) -> std::io::Result<Vec<u8>> {
+    let mut stream = UnixStream::connect(socket_path)?;
+    writeln!(stream, "{}", format.command(cmd))?;
+    // the daemon replies once it sees the end of the command
+    stream.shutdown(Shutdown::Write)?;
+    let mut buf = Vec::new();
+    stream.read_to_end(&mut buf)?;
+    Ok(buf)
+}
+
+/// The displays for a reload event, which may have been added or removed
+fn list_displays(socket_path: &Path, format: WireFormat) -> std::io::Result<Vec<DisplayStatus>> {
+    let buf = send_command(socket_path, &BacklightCommand::List, format)?;
+    match format.parse_reply(&buf) {
+        Ok(BacklightReply::Ok(displays)) => Ok(displays),
+        _ => Err(std::io::Error::other(
+            String::from_utf8_lossy(&buf).trim_end().to_owned(),
+        )),
+    }
+}
+
+/// Prints the initial state and then every change until the daemon goes
+/// away. Text output is passed through as it is, other formats are rendered
+/// again for every change to a selected display.
+fn subscribe(
+    socket_path: &Path,
+    format: WireFormat,
+    target: &TargetDisplay,
+    output: OutputFormat,
+) -> std::io::Result<ExitCode> {
+    let mut stream = UnixStream::connect(socket_path)?;
+    writeln!(stream, "{}", format.command(&BacklightCommand::Subscribe))?;
+    let mut lines = BufReader::new(stream).lines();
+    let Some(line) = lines.next().transpose()? else {
+        return Ok(ExitCode::FAILURE);
+    };
+    let mut displays = match format.parse_reply(line.as_bytes()) {
+        Ok(BacklightReply::Ok(displays)) => displays,
+        _ => {
+            eprintln!("{line}");
+            return Ok(ExitCode::FAILURE);
+        }
+    };
+    if output == OutputFormat::Text {
+        println!("{line}");
+        for line in lines {
+            println!("{}", line?);
+        }
+        return Ok(ExitCode::SUCCESS);
+    }
+    displays.retain(|d| selects(target, &d.name));
+    println!("{}", render(&displays, output));
+    for line in lines {
+        let line = line?;
+        match format.parse_event(line.as_bytes()) {
+            Ok(BacklightEvent::Changed(d)) if selects(target, &d.name) => {
+                match displays.iter_mut().find(|old| old.name == d.name) {
+                    Some(old) => *old = d,
+                    None => displays.push(d),
+                }
+            }
+            Ok(BacklightEvent::Reloaded) => {
+                displays = list_displays(socket_path, format)?;
+                displays.retain(|d| selects(target, &d.name));
+            }
+            Ok(_) => continue,
+            Err(_) => {
+                eprintln!("Could not parse event: {line}");
+                continue;
+            }
+        }
+        println!("{}", render(&displays, output));
+    }
+    Ok(ExitCode::SUCCESS)
+}
+
+fn main() -> ExitCode {
+    let options = CtlOptions::parse();
+    let Some(socket_path) = options.socket_path.or_else(default_socket_path) else {
+        eprintln!("No socket path given and XDG_RUNTIME_DIR is not set");
+        return ExitCode::FAILURE;
+    };
+    let format = if options.json {
+        WireFormat::Json
+    } else {
+        WireFormat::Text
+    };
+    let output = options.command.output_format();
+    if let CtlCommand::Subscribe { ref display, .. } = options.command {
+        return subscribe(&socket_path, format, display, output).unwrap_or_else(|e| {
+            eprintln!("Could not talk to backlightd at {socket_path:?}: {e}");
+            ExitCode::FAILURE
+        });
+    }
+    let cmd = match BacklightCommand::try_from(options.command) {
+        Ok(cmd) => cmd,
+        Err(e) => {
+            eprintln!("{e}");
+            return ExitCode::FAILURE;
+        }
+    };
+    let wire = format.for_command(&cmd);
+    let buf = match send_command(&socket_path, &cmd, wire) {
+        Ok(buf) => buf,
+        Err(e) => {
+            eprintln!("Could not talk to backlightd at {socket_path:?}: {e}");
+            return ExitCode::FAILURE;
+        }
+    };
+    match wire.parse_reply(&buf) {
+        Ok(BacklightReply::Ok(displays)) if output != OutputFormat::Text => {
+            println!("{}", render(&displays, output));
+            ExitCode::SUCCESS
+        }
+        Ok(reply) if reply.is_ok() => {
+            println!("{}", format.reply(&reply));
+            ExitCode::SUCCESS
+        }
+        Ok(reply) => {
+            eprintln!("{}", format.reply(&reply));
+            ExitCode::FAILURE
+        }
+        Err(_) => {
+            eprintln!(
+                "Could not parse reply: {}",
+                String::from_utf8_lossy(&buf).trim_end()
+            );
+            ExitCode::FAILURE
+        }
+    }
+}
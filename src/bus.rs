@@ -0,0 +1,56 @@
+use log::debug;
+use std::time::Duration;
+use zbus::blocking::{connection, Connection};
+
+/// Where a BusConnection connects to
+#[derive(Debug, Clone)]
+pub enum Bus {
+    Session,
+    System,
+    /// A bus at this address, as for tests
+    Address(String),
+}
+
+/// A D-Bus connection made on first use. After a failed call it should be
+/// reset, so that it is made again next time, as the bus may have restarted.
+pub struct BusConnection {
+    bus: Bus,
+    /// Who is called over the connection, for the log
+    peer: &'static str,
+    /// How long a method call may take
+    timeout: Duration,
+    connection: Option<Connection>,
+}
+
+impl BusConnection {
+    pub fn new(bus: Bus, peer: &'static str, timeout: Duration) -> Self {
+        Self {
+            bus,
+            peer,
+            timeout,
+            connection: None,
+        }
+    }
+    /// Connects unless already connected
+    pub fn get(&mut self) -> zbus::Result<&Connection> {
+        let connection = match self.connection.take() {
+            Some(c) => c,
+            None => {
+                debug!("Connecting to {}", self.peer);
+                self.connect()?
+            }
+        };
+        Ok(self.connection.insert(connection))
+    }
+    pub fn reset(&mut self) {
+        self.connection = None;
+    }
+    fn connect(&self) -> zbus::Result<Connection> {
+        let builder = match self.bus {
+            Bus::Session => connection::Builder::session()?,
+            Bus::System => connection::Builder::system()?,
+            Bus::Address(ref a) => connection::Builder::address(a.as_str())?,
+        };
+        builder.method_timeout(self.timeout).build()
+    }
+}
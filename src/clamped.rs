@@ -1,3 +1,4 @@
+use backlightd::ClampState;
 use std::ops::{Add, AddAssign, Deref, Sub, SubAssign};
 
 #[derive(Debug, Clone, Copy)]
@@ -26,6 +27,13 @@ impl<T: Ord> ClampedValue<T> {
     pub fn is_intermediate(&self) -> bool {
         matches!(self, Self::Intermediate(_))
     }
+    pub fn state(&self) -> ClampState {
+        match self {
+            Self::Min(_) => ClampState::Min,
+            Self::Max(_) => ClampState::Max,
+            Self::Intermediate(_) => ClampState::Intermediate,
+        }
+    }
     pub fn map<F, A>(self, f: F) -> ClampedValue<A>
     where
         F: FnOnce(T) -> A,
@@ -55,7 +63,7 @@ impl<T: Ord + Copy> ClampedValue<T> {
         };
     }
     pub fn swap(&mut self, value: T) -> Self {
-        let r = self.clone();
+        let r = *self;
         self.replace(value);
         r
     }
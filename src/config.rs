@@ -1,21 +1,110 @@
-use crate::{Anything, BrightnessScale, ControlMethod, Display, Error, ScaleBuilder};
+use crate::{
+    notify::Notifier,
+    osd::{Osd, OsdTarget},
+    scale::FadeCurve,
+    writer::{Fade, Writer},
+    Anything, BrightnessScale, ControlMethod, Display, Error, Led, ScaleBuilder, DEFAULT_LEVEL,
+    STEPS_IN_REFERENCE_RANGE,
+};
+use std::cell::RefCell;
 use std::path::{Path, PathBuf};
+use std::time::Duration;
 use log::LevelFilter;
 use toml::*;
 
+/// Where backlight: control methods are looked for, as
+/// <sysfs_root>/class/backlight/<name>
+const SYSFS_ROOT: &str = "/sys";
+
+/// Brightness set on startup
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum StartupLevel {
+    /// default_level for every display
+    Default,
+    /// The levels saved in the state file, default_level for displays that
+    /// have no saved level
+    Restore,
+}
+
 pub struct Config {
     pub log_level: LevelFilter,
     pub log_timestamp: bool,
     pub steps_in_reference_range: f32,
-    pub default_level: i8,
+    pub default_level: f32,
+    pub fine_step: f32,
+    /// Screens, then LEDs
     pub displays: Vec<Display>,
     pub socket_path: Option<PathBuf>,
+    /// Where brightness levels are saved, read on startup only
+    pub state_file: Option<PathBuf>,
+    pub startup_level: StartupLevel,
+    /// Feedback for up and down on all displays
+    pub osd: Option<Osd>,
+    /// Desktop notifications after brightness changes
+    pub notifier: Option<Notifier>,
+    /// The file this config was read from, if any
+    pub path: Option<PathBuf>,
+    /// Maximum brightness read from displays whose config gives none
+    pub probed: Vec<ProbedMax>,
+}
+
+/// A maximum brightness asked of a display, kept so that a reload need not
+/// ask again
+#[derive(Debug, PartialEq, Clone)]
+pub struct ProbedMax {
+    pub name: String,
+    pub control: ControlMethod,
+    pub max: usize,
+}
+
+/// A duration for each kind of brightness control
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct MethodDurations {
+    pub sysfs: Duration,
+    pub ddcutil: Duration,
+    pub ddc: Duration,
+}
+
+impl MethodDurations {
+    /// Least time between two brightness writes to a display. Values that
+    /// come sooner are coalesced.
+    pub const WRITE_INTERVALS: Self = Self {
+        sysfs: Duration::ZERO,
+        ddcutil: Duration::from_millis(100),
+        ddc: Duration::from_millis(100),
+    };
+    /// How long a change of brightness takes, zero for none. Monitors are
+    /// too slow to take a fade.
+    pub const FADES: Self = Self {
+        sysfs: Duration::from_millis(200),
+        ddcutil: Duration::ZERO,
+        ddc: Duration::ZERO,
+    };
+    /// None for controls that cannot set brightness
+    pub fn for_method(&self, method: &ControlMethod) -> Option<Duration> {
+        match method {
+            ControlMethod::SysFS(_) | ControlMethod::Backlight(_) | ControlMethod::Logind(_) => {
+                Some(self.sysfs)
+            }
+            ControlMethod::DDCUtil(_) => Some(self.ddcutil),
+            ControlMethod::Ddc(_) => Some(self.ddc),
+            ControlMethod::SwayDPMS(_) => None,
+        }
+    }
 }
 
 impl Config {
     pub fn mut_displays(&mut self) -> &mut [Display] {
         self.displays.as_mut_slice()
     }
+    /// The maximum probed for the display called name, if its control is
+    /// still the same
+    fn probed_max(&self, name: &str, control: &ControlMethod) -> Option<usize> {
+        self.probed
+            .iter()
+            .find(|p| p.name == name && p.control == *control)
+            .map(|p| p.max)
+    }
 }
 
 mod parser {
@@ -23,39 +112,89 @@ mod parser {
     use nom::{
         branch::alt,
         bytes::complete::tag_no_case,
-        character::complete::{char, digit1},
+        character::complete::{alpha1, char, digit1},
         combinator::{map, map_res, opt, recognize, rest},
-        sequence::{preceded, tuple},
+        sequence::{preceded, separated_pair, tuple},
     };
 
-    use crate::{scale::ScaleKind, ControlMethod};
+    use crate::{
+        osd::OsdTarget,
+        scale::{FadeCurve, ScaleKind},
+        ControlMethod,
+    };
     use log::LevelFilter;
+    use std::path::Path;
     type ParseResult<'a, T> = nom::IResult<&'a str, T>;
 
-    fn number_p<T: std::str::FromStr>(input: &str) -> ParseResult<T> {
+    fn number_p<T: std::str::FromStr>(input: &str) -> ParseResult<'_, T> {
         let dot_p = preceded(char('.'), digit1);
         let p = recognize(tuple((digit1, opt(dot_p))));
         map_res(p, |x: &str| x.parse::<T>())(input)
     }
-    fn sysfs(input: &str) -> ParseResult<ControlMethod> {
+    fn sysfs(input: &str) -> ParseResult<'_, ControlMethod> {
         let p = preceded(tag_no_case("sysfs:"), rest);
         map(p, |x: &str| ControlMethod::SysFS(x.into()))(input)
     }
-    fn ddcutil(input: &str) -> ParseResult<ControlMethod> {
+    fn ddcutil(input: &str) -> ParseResult<'_, ControlMethod> {
         let p = preceded(tag_no_case("ddcutil:"), number_p);
         map(p, |x: u8| ControlMethod::DDCUtil(x))(input)
     }
-    fn swaydpms(input: &str) -> ParseResult<ControlMethod> {
+    fn ddc(input: &str) -> ParseResult<'_, ControlMethod> {
+        let p = preceded(tag_no_case("ddc:"), rest);
+        map(p, |x: &str| ControlMethod::Ddc(x.into()))(input)
+    }
+    /// Only the subsystem and name, the caller resolves them against the
+    /// sysfs root
+    fn backlight(input: &str) -> ParseResult<'_, ControlMethod> {
+        let p = preceded(tag_no_case("backlight:"), rest);
+        map(p, |x: &str| ControlMethod::Backlight(Path::new("backlight").join(x)))(input)
+    }
+    fn leds(input: &str) -> ParseResult<'_, ControlMethod> {
+        let p = preceded(tag_no_case("leds:"), rest);
+        map(p, |x: &str| ControlMethod::Backlight(Path::new("leds").join(x)))(input)
+    }
+    /// "logind:<subsystem>:<name>", resolved like backlight
+    fn logind(input: &str) -> ParseResult<'_, ControlMethod> {
+        let p = preceded(tag_no_case("logind:"), separated_pair(alpha1, char(':'), rest));
+        map(p, |(subsystem, name): (&str, &str)| {
+            ControlMethod::Logind(Path::new(subsystem).join(name))
+        })(input)
+    }
+    fn swaydpms(input: &str) -> ParseResult<'_, ControlMethod> {
         let p = preceded(tag_no_case("swaydpms:"), rest);
         map(p, |x: &str| ControlMethod::SwayDPMS(x.into()))(input)
     }
     pub fn parse_control_method(input: &str) -> Result<ControlMethod, Error> {
-        match alt((sysfs, ddcutil, swaydpms))(input) {
+        match alt((sysfs, ddcutil, ddc, backlight, leds, logind, swaydpms))(input) {
             Ok((_, v)) => Ok(v),
             Err(_) => Err(Error::BadConfiguration("Could not parse control method")),
         }
     }
-    pub fn loglevel(input: &str) -> ParseResult<LevelFilter> {
+    fn fifo(input: &str) -> ParseResult<'_, OsdTarget> {
+        let p = preceded(tag_no_case("fifo:"), rest);
+        map(p, |x: &str| OsdTarget::Fifo(x.into()))(input)
+    }
+    fn pipe(input: &str) -> ParseResult<'_, OsdTarget> {
+        let p = preceded(tag_no_case("pipe:"), rest);
+        map(p, |x: &str| OsdTarget::Pipe(x.into()))(input)
+    }
+    pub fn parse_fade_curve(input: &str) -> Result<FadeCurve, Error> {
+        match input {
+            "linear" => Ok(FadeCurve::Linear),
+            "ease-out" => Ok(FadeCurve::EaseOut),
+            "ease-in-out" => Ok(FadeCurve::EaseInOut),
+            _ => Err(Error::BadConfiguration(
+                "fade curve must be \"linear\", \"ease-out\" or \"ease-in-out\"",
+            )),
+        }
+    }
+    pub fn parse_osd_target(input: &str) -> Result<OsdTarget, Error> {
+        match alt((fifo, pipe))(input) {
+            Ok((_, v)) => Ok(v),
+            Err(_) => Err(Error::BadConfiguration("Could not parse osd target")),
+        }
+    }
+    pub fn loglevel(input: &str) -> ParseResult<'_, LevelFilter> {
         let off_p = map(tag_no_case("off"), |_| LevelFilter::Off);
         let error_p = map(tag_no_case("error"), |_| LevelFilter::Error);
         let warn_p = map(tag_no_case("warn"), |_| LevelFilter::Warn);
@@ -89,6 +228,27 @@ mod parser {
             assert_eq!(v, expected);
         }
         #[test]
+        fn test_osd_parsing() {
+            let v = parse_osd_target("fifo:/run/user/1000/wob.sock").unwrap();
+            assert_eq!(v, OsdTarget::Fifo("/run/user/1000/wob.sock".into()));
+            let v = parse_osd_target("pipe:wob --anchor bottom").unwrap();
+            assert_eq!(v, OsdTarget::Pipe("wob --anchor bottom".into()));
+            assert!(parse_osd_target("/run/user/1000/wob.sock").is_err());
+        }
+        #[test]
+        fn test_ddc_parsing() {
+            let v = parse_control_method("ddc:/dev/i2c-5").unwrap();
+            assert_eq!(v, ControlMethod::Ddc("/dev/i2c-5".into()));
+            let v = parse_control_method("ddcutil:2").unwrap();
+            assert_eq!(v, ControlMethod::DDCUtil(2));
+            let v = parse_control_method("backlight:intel_backlight").unwrap();
+            assert_eq!(v, ControlMethod::Backlight("backlight/intel_backlight".into()));
+            let v = parse_control_method("leds:tpacpi::kbd_backlight").unwrap();
+            assert_eq!(v, ControlMethod::Backlight("leds/tpacpi::kbd_backlight".into()));
+            let v = parse_control_method("logind:leds:tpacpi::kbd_backlight").unwrap();
+            assert_eq!(v, ControlMethod::Logind("leds/tpacpi::kbd_backlight".into()));
+        }
+        #[test]
         fn test_sysfs_parsing() {
             let (_, v) = sysfs("sysfs:/path/to/file").unwrap();
             let expected = ControlMethod::SysFS("/path/to/file".into());
@@ -112,7 +272,138 @@ fn get_usize(table: &Table, key: &str) -> Result<Option<usize>, Error> {
     }
 }
 
-fn toml_to_display(t: &Table) -> Result<Display, Error> {
+fn get_osd(table: &Table) -> Result<Option<Osd>, Error> {
+    let Some(v) = table.get("osd") else {
+        return Ok(None);
+    };
+    let s = v
+        .as_str()
+        .ok_or(Error::BadConfiguration("Could not parse osd configuration"))?;
+    Ok(Some(Osd::new(parse_osd_target(s)?)))
+}
+
+// Accepts integers as well, so that "default_level = 4" works
+fn get_f32(table: &Table, key: &str) -> Result<Option<f32>, Error> {
+    let Some(x) = table.get(key) else {
+        return Ok(None);
+    };
+    match (x.as_float(), x.as_integer()) {
+        (Some(y), _) => Ok(Some(y as f32)),
+        (_, Some(y)) => Ok(Some(y as f32)),
+        _ => Err(Error::BadConfiguration("Could not parse number value")),
+    }
+}
+
+/// How displays are written to, from [write_interval] and [fade]
+struct WriteSettings {
+    intervals: MethodDurations,
+    fades: MethodDurations,
+    curve: FadeCurve,
+}
+
+impl WriteSettings {
+    fn fade_for(&self, method: &ControlMethod) -> Option<Fade> {
+        let duration = self.fades.for_method(method)?;
+        (!duration.is_zero()).then_some(Fade {
+            duration,
+            curve: self.curve,
+        })
+    }
+}
+
+/// Milliseconds for sysfs, ddcutil and ddc, each optional
+fn get_method_durations(t: &Table, mut durations: MethodDurations) -> Result<MethodDurations, Error> {
+    let fields = [
+        ("sysfs", &mut durations.sysfs),
+        ("ddcutil", &mut durations.ddcutil),
+        ("ddc", &mut durations.ddc),
+    ];
+    for (key, field) in fields {
+        if let Some(ms) = get_usize(t, key)? {
+            *field = Duration::from_millis(ms as u64);
+        }
+    }
+    Ok(durations)
+}
+
+fn get_write_settings(doc: &Table) -> Result<WriteSettings, Error> {
+    let mut settings = WriteSettings {
+        intervals: MethodDurations::WRITE_INTERVALS,
+        fades: MethodDurations::FADES,
+        curve: FadeCurve::Linear,
+    };
+    if let Some(v) = doc.get("write_interval") {
+        let t = v
+            .as_table()
+            .ok_or(Error::BadConfiguration("write_interval must be a table"))?;
+        settings.intervals = get_method_durations(t, settings.intervals)?;
+    }
+    if let Some(v) = doc.get("fade") {
+        let t = v
+            .as_table()
+            .ok_or(Error::BadConfiguration("fade must be a table"))?;
+        settings.fades = get_method_durations(t, settings.fades)?;
+        settings.curve = match t.get("curve").map(|v| v.as_str()) {
+            None => FadeCurve::Linear,
+            Some(Some(s)) => parse_fade_curve(s)?,
+            Some(None) => return Err(Error::BadConfiguration("Could not parse fade curve")),
+        };
+    }
+    Ok(settings)
+}
+
+/// Subsystem and name of a backlight or LED as given in the config
+fn class_device(p: &Path) -> Result<(&str, &str), Error> {
+    let parts: Vec<&str> = p.iter().filter_map(|c| c.to_str()).collect();
+    match parts[..] {
+        [subsystem, name] => Ok((subsystem, name)),
+        _ => Err(Error::BadConfiguration("Could not parse device name")),
+    }
+}
+
+/// Backlights and LEDs are named in the config, and found under sysfs_root
+fn resolve_control_method(cm: ControlMethod, sysfs_root: &Path) -> Result<ControlMethod, Error> {
+    match cm {
+        ControlMethod::Backlight(p) => {
+            let (subsystem, name) = class_device(&p)?;
+            ControlMethod::new_backlight(sysfs_root, subsystem, name)
+        }
+        ControlMethod::Logind(p) => {
+            let (subsystem, name) = class_device(&p)?;
+            ControlMethod::new_logind(sysfs_root, subsystem, name)
+        }
+        cm => Ok(cm),
+    }
+}
+
+/// follow and off_level of an [[led]] table, the rest is as for a display
+fn toml_to_led(t: &Table) -> Result<Led, Error> {
+    let follow = match t.get("follow") {
+        None => false,
+        Some(v) => v
+            .as_bool()
+            .ok_or(Error::BadConfiguration("follow must be true or false"))?,
+    };
+    Ok(Led {
+        follow,
+        off_level: get_f32(t, "off_level")?,
+    })
+}
+
+/// Asks the brightness control of the display called name for its maximum
+/// value
+type MaxProbe<'a> = &'a dyn Fn(&str, &ControlMethod) -> Option<usize>;
+
+/// steps and default_level apply unless the display table overrides them.
+/// Without a max in the table, the control is probed for one.
+fn toml_to_display(
+    t: &Table,
+    steps: f32,
+    default_level: f32,
+    probe: MaxProbe,
+    settings: &WriteSettings,
+    sysfs_root: &Path,
+) -> Result<Display, Error> {
     let Some(name) = t.get("name").and_then(|v| v.as_str()) else {
         return Err(Error::BadConfiguration("Display name is required"));
     };
@@ -123,7 +414,7 @@ fn toml_to_display(t: &Table) -> Result<Display, Error> {
             "Could not parse brightness control configuration",
         ))?;
         let cm = parser::parse_control_method(s)?;
-        brightness_control = Some(cm);
+        brightness_control = Some(resolve_control_method(cm, sysfs_root)?);
     }
     let mut onoff_control: Option<ControlMethod> = None;
     if let Some(v) = t.get("onoff_control") {
@@ -131,7 +422,13 @@ fn toml_to_display(t: &Table) -> Result<Display, Error> {
             "Could not parse onoff control configuration",
         ))?;
         let cm = parser::parse_control_method(s)?;
-        onoff_control = Some(cm);
+        onoff_control = Some(resolve_control_method(cm, sysfs_root)?);
+    }
+    // a backlight is turned on and off through its own bl_power
+    if let (None, Some(ControlMethod::Backlight(p))) = (&onoff_control, &brightness_control) {
+        if matches!(p.join("bl_power").try_exists(), Ok(true)) {
+            onoff_control = brightness_control.clone();
+        }
     }
     // build the scale
     let gamma = t.get("gamma").and_then(|v| v.as_float());
@@ -139,7 +436,11 @@ fn toml_to_display(t: &Table) -> Result<Display, Error> {
     let max_value = get_usize(t, "max")?;
     let ref_max = get_usize(t, "ref_max")?;
     let ref_min = get_usize(t, "ref_min")?;
+    let steps = get_f32(t, "steps")?.unwrap_or(steps);
+    let default_level = get_f32(t, "default_level")?.unwrap_or(default_level);
     let mut scalebuilder = ScaleBuilder::new();
+    scalebuilder.steps(steps);
+    scalebuilder.default_level(default_level);
     if let Some(g) = gamma {
         if g == 1.0 {
             scalebuilder.kind(crate::scale::ScaleKind::Linear);
@@ -153,8 +454,16 @@ fn toml_to_display(t: &Table) -> Result<Display, Error> {
     if let Some(v) = min_value {
         scalebuilder.min_value(v);
     }
-    if let Some(v) = max_value {
-        scalebuilder.max_value(v);
+    match (max_value, &brightness_control) {
+        (Some(v), _) => {
+            scalebuilder.max_value(v);
+        }
+        (None, Some(c)) => {
+            if let Some(v) = probe(name, c) {
+                scalebuilder.max_value(v);
+            }
+        }
+        (None, None) => (),
     }
     if let Some(v) = ref_max {
         scalebuilder.ref_max_value(v);
@@ -163,15 +472,25 @@ fn toml_to_display(t: &Table) -> Result<Display, Error> {
         scalebuilder.ref_min_value(v);
     }
     let scale = scalebuilder.make()?;
+    let writer = brightness_control.as_ref().and_then(|c| {
+        let interval = settings.intervals.for_method(c)?;
+        let write = crate::brightness_writer(c.clone());
+        Some(Writer::new(format!("{name:?}"), interval, write))
+    });
+    let fade = brightness_control.as_ref().and_then(|c| settings.fade_for(c));
     Ok(Display {
         dpms_control: onoff_control,
         brightness_control,
         scale,
+        writer,
+        fade,
+        led: None,
+        osd: get_osd(t)?,
         name: name.into(),
     })
 }
 
-fn parse_config_document(document: impl AsRef<str>) -> Result<Config, Error> {
+fn parse_config_document(document: impl AsRef<str>, probe: MaxProbe) -> Result<Config, Error> {
     let doc = document
         .as_ref()
         .parse::<Table>()
@@ -186,38 +505,84 @@ fn parse_config_document(document: impl AsRef<str>) -> Result<Config, Error> {
     let display_config = doc.get("display").ok_or(Error::BadConfiguration(
         "Could not find a display array in the configuration document",
     ))?;
+    let steps = get_f32(&doc, "steps")?.unwrap_or(STEPS_IN_REFERENCE_RANGE);
+    let default_level = get_f32(&doc, "default_level")?.unwrap_or(DEFAULT_LEVEL);
+    let fine_step = get_f32(&doc, "fine_step")?.unwrap_or(0.25);
     let displays_array = display_config.as_array().ok_or(Error::BadConfiguration(
         "Could not parse the display array in the configuration document",
     ))?;
+    let settings = get_write_settings(&doc)?;
+    let sysfs_root = doc
+        .get("sysfs_root")
+        .and_then(|v| v.as_str())
+        .map(PathBuf::from)
+        .unwrap_or_else(|| PathBuf::from(SYSFS_ROOT));
     let mut displays = Vec::new();
     for display_config in displays_array {
         let display_toml_table = display_config.as_table().ok_or(Error::BadConfiguration(
             "Could not parse toml display table",
         ))?;
-        let display = toml_to_display(display_toml_table)?;
+        let display = toml_to_display(
+            display_toml_table,
+            steps,
+            default_level,
+            probe,
+            &settings,
+            &sysfs_root,
+        )?;
         displays.push(display);
     }
-    let steps = doc
-        .get("steps")
-        .and_then(|v| v.as_integer())
-        .map(|v| v as f32)
-        .unwrap_or(9.0);
-    let default_level = doc
-        .get("default_level")
-        .and_then(|v| v.as_integer())
-        .map(|v| v as i8)
-        .unwrap_or(4);
+    if let Some(led_config) = doc.get("led") {
+        let leds_array = led_config.as_array().ok_or(Error::BadConfiguration(
+            "Could not parse the led array in the configuration document",
+        ))?;
+        for led_config in leds_array {
+            let t = led_config
+                .as_table()
+                .ok_or(Error::BadConfiguration("Could not parse toml led table"))?;
+            let mut led =
+                toml_to_display(t, steps, default_level, probe, &settings, &sysfs_root)?;
+            led.led = Some(toml_to_led(t)?);
+            displays.push(led);
+        }
+    }
     let socket_path = doc
         .get("socket_path")
         .and_then(|v| v.as_str())
-        .map(|v| PathBuf::from(v));
+        .map(PathBuf::from);
+    let state_file = doc
+        .get("state_file")
+        .and_then(|v| v.as_str())
+        .map(PathBuf::from);
+    let notify = match doc.get("notify") {
+        None => false,
+        Some(v) => v
+            .as_bool()
+            .ok_or(Error::BadConfiguration("notify must be true or false"))?,
+    };
+    let startup_level = match doc.get("startup_level").map(|v| v.as_str()) {
+        None | Some(Some("restore")) => StartupLevel::Restore,
+        Some(Some("default")) => StartupLevel::Default,
+        _ => {
+            return Err(Error::BadConfiguration(
+                "startup_level must be \"restore\" or \"default\"",
+            ))
+        }
+    };
     Ok(Config {
         log_level,
         log_timestamp,
         steps_in_reference_range: steps,
         default_level,
+        fine_step,
         displays,
         socket_path,
+        state_file,
+        startup_level,
+        osd: get_osd(&doc)?,
+        notifier: notify.then(Notifier::new),
+        path: None,
+        probed: Vec::new(),
     })
 }
 
@@ -227,13 +592,28 @@ fn get_config_file_contents(p: impl AsRef<Path>) -> Result<String, Error> {
     Ok(contents)
 }
 
-fn get_config_file_path() -> Result<PathBuf, Error> {
+/// An explicitly given config file must exist. Otherwise the first of
+/// $XDG_CONFIG_HOME/backlightd/config (or ~/.config/backlightd/config) and
+/// /etc/backlightd/config that exists is used.
+fn get_config_file_path(explicit: Option<&Path>) -> Result<PathBuf, Error> {
+    if let Some(p) = explicit {
+        return match p.try_exists() {
+            Ok(true) => Ok(p.into()),
+            _ => Err(Error::BadPath(p.into())),
+        };
+    }
     let mut targets: Vec<PathBuf> = Vec::new();
     if let Ok(xdg_user_config) = std::env::var("XDG_CONFIG_HOME") {
         let mut p = PathBuf::from(xdg_user_config);
         p.push("backlightd");
         p.push("config");
         targets.push(p);
+    } else if let Ok(home) = std::env::var("HOME") {
+        let mut p = PathBuf::from(home);
+        p.push(".config");
+        p.push("backlightd");
+        p.push("config");
+        targets.push(p);
     }
     targets.push(PathBuf::from("/etc/backlightd/config"));
     for target in targets {
@@ -244,8 +624,205 @@ fn get_config_file_path() -> Result<PathBuf, Error> {
     Err(Error::NoConfigFile)
 }
 
-pub fn get_config() -> Result<Config, Error> {
-    let config_path = get_config_file_path()?;
-    let contents = get_config_file_contents(config_path)?;
-    parse_config_document(contents)
+/// Displays are only asked for their maximum brightness if previous, the
+/// config being replaced, has not already done so
+pub fn get_config(explicit_path: Option<&Path>, previous: Option<&Config>) -> Result<Config, Error> {
+    let config_path = get_config_file_path(explicit_path)?;
+    let contents = get_config_file_contents(&config_path)?;
+    let probed = RefCell::new(Vec::new());
+    let probe = |name: &str, control: &ControlMethod| {
+        let known = previous.and_then(|p| p.probed_max(name, control));
+        let max = known.or_else(|| crate::read_max_brightness(control))?;
+        probed.borrow_mut().push(ProbedMax {
+            name: name.into(),
+            control: control.clone(),
+            max,
+        });
+        Some(max)
+    };
+    let mut config = parse_config_document(contents, &probe)?;
+    config.path = Some(config_path);
+    config.probed = probed.into_inner();
+    Ok(config)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DOCUMENT: &str = r#"
+        steps = 20
+        default_level = 10
+
+        [[display]]
+        name = "laptop"
+        brightness_control = "sysfs:/path/to/brightness"
+        max = 1000
+
+        [[display]]
+        name = "desktop"
+        brightness_control = "ddcutil:1"
+        gamma = 1.0
+        steps = 9
+        default_level = 4.5
+    "#;
+
+    #[test]
+    fn test_explicit_config_path_must_exist() {
+        let p = Path::new("/nonexistent/backlightd/config");
+        assert!(matches!(get_config_file_path(Some(p)), Err(Error::BadPath(_))));
+    }
+    #[test]
+    fn test_steps_and_default_level() {
+        let config = parse_config_document(DOCUMENT, &|_, _| None).unwrap();
+        assert_eq!(config.steps_in_reference_range, 20.0);
+        assert_eq!(config.default_level, 10.0);
+        assert_eq!(config.startup_level, StartupLevel::Restore);
+        let laptop = &config.displays[0].scale;
+        assert_eq!(laptop.steps(), 20.0);
+        assert_eq!(laptop.default_level(), 10.0);
+        let desktop = &config.displays[1].scale;
+        assert_eq!(desktop.steps(), 9.0);
+        assert_eq!(desktop.default_level(), 4.5);
+    }
+    #[test]
+    fn test_write_settings() {
+        let doc: Table = "[write_interval]\nddcutil = 400\nsysfs = 5".parse().unwrap();
+        let settings = get_write_settings(&doc).unwrap();
+        let intervals = settings.intervals;
+        assert_eq!(intervals.sysfs, Duration::from_millis(5));
+        assert_eq!(intervals.ddcutil, Duration::from_millis(400));
+        assert_eq!(intervals.ddc, MethodDurations::WRITE_INTERVALS.ddc);
+        assert_eq!(
+            intervals.for_method(&ControlMethod::SwayDPMS("DP-3".into())),
+            None
+        );
+        let doc: Table = "write_interval = 100".parse().unwrap();
+        assert!(get_write_settings(&doc).is_err());
+        // fades are on for sysfs only, unless configured
+        let sysfs = ControlMethod::SysFS("/sys/class/backlight/x/brightness".into());
+        let ddc = ControlMethod::Ddc("/dev/i2c-5".into());
+        let settings = get_write_settings(&Table::new()).unwrap();
+        assert!(settings.fade_for(&sysfs).is_some());
+        assert_eq!(settings.fade_for(&ddc), None);
+        let doc: Table = "[fade]\nsysfs = 0\nddc = 300\ncurve = \"ease-out\"".parse().unwrap();
+        let settings = get_write_settings(&doc).unwrap();
+        assert_eq!(settings.fade_for(&sysfs), None);
+        assert_eq!(
+            settings.fade_for(&ddc),
+            Some(Fade {
+                duration: Duration::from_millis(300),
+                curve: FadeCurve::EaseOut
+            })
+        );
+        let doc: Table = "[fade]\ncurve = \"bounce\"".parse().unwrap();
+        assert!(get_write_settings(&doc).is_err());
+    }
+    #[test]
+    fn test_backlight_discovery() {
+        let root = std::env::temp_dir().join(format!("backlightd-sysfs-{}", std::process::id()));
+        let dir = root.join("class/backlight/acpi_video0");
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("max_brightness"), "937\n").unwrap();
+        std::fs::write(dir.join("bl_power"), "0\n").unwrap();
+        let document = format!(
+            "sysfs_root = {:?}\n[[display]]\nname = \"laptop\"\nbrightness_control = \"backlight:acpi_video0\"",
+            root.to_str().unwrap()
+        );
+        let config = parse_config_document(&document, &|_, c| crate::read_max_brightness(c)).unwrap();
+        let laptop = &config.displays[0];
+        assert_eq!(laptop.brightness_control, Some(ControlMethod::Backlight(dir.clone())));
+        assert_eq!(laptop.dpms_control, laptop.brightness_control);
+        assert_eq!(*laptop.scale.value_for(laptop.scale.max_level()), 937);
+        let missing = document.replace("acpi_video0", "nv_backlight");
+        assert!(parse_config_document(&missing, &|_, _| None).is_err());
+        // the same device, written through logind
+        let document = document.replace("backlight:", "logind:backlight:");
+        let config = parse_config_document(&document, &|_, c| crate::read_max_brightness(c)).unwrap();
+        let laptop = &config.displays[0];
+        let control = laptop.brightness_control.as_ref().unwrap();
+        assert_eq!(*control, ControlMethod::Logind(dir.clone()));
+        assert_eq!(control.to_string(), "logind:backlight:acpi_video0");
+        assert_eq!(laptop.dpms_control, None);
+        assert_eq!(*laptop.scale.value_for(laptop.scale.max_level()), 937);
+        std::fs::remove_dir_all(root).unwrap();
+    }
+    #[test]
+    fn test_leds() {
+        let root = std::env::temp_dir().join(format!("backlightd-leds-{}", std::process::id()));
+        let dir = root.join("class/leds/tpacpi::kbd_backlight");
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("max_brightness"), "2\n").unwrap();
+        let document = format!(
+            r#"
+            sysfs_root = {:?}
+            [[led]]
+            name = "keyboard"
+            brightness_control = "leds:tpacpi::kbd_backlight"
+            follow = true
+            off_level = 7
+            [[display]]
+            name = "laptop"
+            brightness_control = "sysfs:/path/to/brightness"
+            max = 1000
+            "#,
+            root.to_str().unwrap()
+        );
+        let config = parse_config_document(&document, &|_, c| crate::read_max_brightness(c)).unwrap();
+        // LEDs come after the screens, whatever the order in the file
+        let [laptop, keyboard] = &config.displays[..] else {
+            panic!("expected two entries");
+        };
+        assert_eq!(laptop.led, None);
+        assert_eq!(
+            keyboard.led,
+            Some(Led {
+                follow: true,
+                off_level: Some(7.0)
+            })
+        );
+        let control = keyboard.brightness_control.as_ref().unwrap();
+        assert_eq!(control.to_string(), "leds:tpacpi::kbd_backlight");
+        assert_eq!(keyboard.dpms_control, None);
+        assert_eq!(*keyboard.scale.value_for(keyboard.scale.max_level()), 2);
+        std::fs::remove_dir_all(root).unwrap();
+    }
+    #[test]
+    fn test_probed_max() {
+        let config = parse_config_document(DOCUMENT, &|_, c| match c {
+            ControlMethod::DDCUtil(_) => Some(60),
+            _ => Some(5),
+        })
+        .unwrap();
+        // an explicit max wins over what the display reports
+        let laptop = &config.displays[0].scale;
+        assert_eq!(*laptop.value_for(laptop.max_level()), 1000);
+        let desktop = &config.displays[1].scale;
+        assert_eq!(*desktop.value_for(desktop.max_level()), 60);
+    }
+    #[test]
+    fn test_probed_max_carries_over() {
+        let root = std::env::temp_dir().join(format!("backlightd-probe-{}", std::process::id()));
+        let dir = root.join("class/backlight/acpi_video0");
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("max_brightness"), "937\n").unwrap();
+        let path = root.join("config");
+        let document = format!(
+            "sysfs_root = {:?}\n[[display]]\nname = \"laptop\"\nbrightness_control = \"backlight:acpi_video0\"",
+            root.to_str().unwrap()
+        );
+        std::fs::write(&path, &document).unwrap();
+        let config = get_config(Some(&path), None).unwrap();
+        let max = |c: &Config| *c.displays[0].scale.value_for(c.displays[0].scale.max_level());
+        assert_eq!(max(&config), 937);
+        // a reload does not ask the display again
+        std::fs::write(dir.join("max_brightness"), "500\n").unwrap();
+        let reloaded = get_config(Some(&path), Some(&config)).unwrap();
+        assert_eq!(max(&reloaded), 937);
+        // unless it is a different display now
+        std::fs::write(&path, document.replace("laptop", "internal")).unwrap();
+        let renamed = get_config(Some(&path), Some(&reloaded)).unwrap();
+        assert_eq!(max(&renamed), 500);
+        std::fs::remove_dir_all(root).unwrap();
+    }
 }
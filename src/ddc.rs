@@ -0,0 +1,267 @@
+//! DDC/CI over the Linux i2c-dev interface, for monitors that are otherwise
+//! controlled by spawning ddcutil
+use log::{debug, warn};
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, Read, Write},
+    os::unix::io::AsRawFd,
+    path::Path,
+    thread,
+    time::Duration,
+};
+
+/// I2C address of the DDC/CI interface of a monitor
+const DDC_ADDRESS: u16 = 0x37;
+/// from linux/i2c-dev.h
+const I2C_SLAVE: libc::c_ulong = 0x0703;
+/// Address of the host, the source of every message sent to the monitor
+const HOST_ADDRESS: u8 = 0x51;
+/// Address that replies from the monitor are checksummed against
+const REPLY_ADDRESS: u8 = 0x50;
+const GET_VCP: u8 = 0x01;
+const GET_VCP_REPLY: u8 = 0x02;
+const SET_VCP: u8 = 0x03;
+pub const VCP_BRIGHTNESS: u8 = 0x10;
+const ATTEMPTS: usize = 3;
+/// The DDC/CI spec asks for at least 50ms between a set and the next message
+const COMMAND_INTERVAL: Duration = Duration::from_millis(50);
+/// and 40ms between a get request and reading the reply
+const REPLY_DELAY: Duration = Duration::from_millis(40);
+
+/// Current and maximum value of a VCP feature, as read from the monitor
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct VcpValue {
+    pub current: u16,
+    pub max: u16,
+}
+
+/// Raw reads and writes to the monitor, so that tests can stand in for an
+/// i2c device
+pub trait I2c {
+    fn write(&mut self, data: &[u8]) -> io::Result<()>;
+    fn read(&mut self, data: &mut [u8]) -> io::Result<()>;
+}
+
+/// An i2c-dev device node such as /dev/i2c-5, addressing the monitor
+pub struct I2cDev(File);
+
+impl I2cDev {
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let file = OpenOptions::new().read(true).write(true).open(path)?;
+        let r = unsafe {
+            libc::ioctl(
+                file.as_raw_fd(),
+                I2C_SLAVE as _,
+                DDC_ADDRESS as libc::c_ulong,
+            )
+        };
+        if r < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Self(file))
+    }
+}
+
+impl I2c for I2cDev {
+    fn write(&mut self, data: &[u8]) -> io::Result<()> {
+        self.0.write_all(data)
+    }
+    fn read(&mut self, data: &mut [u8]) -> io::Result<()> {
+        self.0.read_exact(data)
+    }
+}
+
+/// XOR of every byte, starting from the destination address as it appears
+/// on the wire
+fn checksum(initial: u8, bytes: &[u8]) -> u8 {
+    bytes.iter().fold(initial, |acc, b| acc ^ b)
+}
+
+fn set_vcp_message(code: u8, value: u16) -> [u8; 7] {
+    let [hi, lo] = value.to_be_bytes();
+    // length byte has the high bit set
+    let mut msg = [HOST_ADDRESS, 0x80 | 4, SET_VCP, code, hi, lo, 0];
+    msg[6] = checksum((DDC_ADDRESS << 1) as u8, &msg[..6]);
+    msg
+}
+
+fn get_vcp_message(code: u8) -> [u8; 5] {
+    let mut msg = [HOST_ADDRESS, 0x80 | 2, GET_VCP, code, 0];
+    msg[4] = checksum((DDC_ADDRESS << 1) as u8, &msg[..4]);
+    msg
+}
+
+fn invalid_reply(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// Checks a get VCP reply: source address, length, opcode, result code, and
+/// the checksum over everything before it
+fn parse_get_vcp_reply(code: u8, reply: &[u8; 11]) -> io::Result<VcpValue> {
+    if reply[1] == 0x80 {
+        // a null message, the monitor was not ready to answer
+        return Err(invalid_reply("monitor sent a null message"));
+    }
+    if checksum(REPLY_ADDRESS, &reply[..10]) != reply[10] {
+        return Err(invalid_reply("bad checksum in DDC reply"));
+    }
+    if reply[1] != 0x80 | 8 || reply[2] != GET_VCP_REPLY || reply[4] != code {
+        return Err(invalid_reply("unexpected DDC reply"));
+    }
+    if reply[3] != 0 {
+        return Err(invalid_reply("monitor does not support this VCP feature"));
+    }
+    Ok(VcpValue {
+        max: u16::from_be_bytes([reply[6], reply[7]]),
+        current: u16::from_be_bytes([reply[8], reply[9]]),
+    })
+}
+
+/// Asks the monitor for a VCP feature, retrying missed or garbled replies
+pub fn get_vcp(bus: &mut impl I2c, code: u8) -> io::Result<VcpValue> {
+    let msg = get_vcp_message(code);
+    let mut attempt = 1;
+    loop {
+        let mut reply = [0u8; 11];
+        let r = bus.write(&msg).and_then(|_| {
+            thread::sleep(REPLY_DELAY);
+            bus.read(&mut reply)
+        });
+        let r = r.and_then(|_| parse_get_vcp_reply(code, &reply));
+        thread::sleep(COMMAND_INTERVAL);
+        match r {
+            Ok(v) => return Ok(v),
+            Err(e) if attempt < ATTEMPTS => {
+                debug!("DDC read failed, attempt {attempt}: {e}");
+                attempt += 1;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Retries failed writes, as monitors regularly miss a message
+pub fn set_vcp(bus: &mut impl I2c, code: u8, value: u16) -> io::Result<()> {
+    let msg = set_vcp_message(code, value);
+    let mut attempt = 1;
+    loop {
+        let r = bus.write(&msg);
+        // the monitor needs time to act before it takes the next message
+        thread::sleep(COMMAND_INTERVAL);
+        match r {
+            Ok(()) => return Ok(()),
+            Err(e) if attempt < ATTEMPTS => {
+                debug!("DDC write failed, attempt {attempt}: {e}");
+                attempt += 1;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Sets VCP 0x10 on the monitor behind the i2c-dev device at path
+pub fn set_brightness(path: &Path, value: usize) -> io::Result<()> {
+    let value = u16::try_from(value)
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "value out of range for DDC"))?;
+    let mut dev = I2cDev::open(path)?;
+    set_vcp(&mut dev, VCP_BRIGHTNESS, value).inspect_err(|e| {
+        warn!("Could not set brightness over DDC on {path:?}: {e}");
+    })
+}
+
+/// Reads VCP 0x10 from the monitor behind the i2c-dev device at path
+pub fn get_brightness(path: &Path) -> io::Result<VcpValue> {
+    let mut dev = I2cDev::open(path)?;
+    get_vcp(&mut dev, VCP_BRIGHTNESS)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Records writes, failing the first few, and answers reads from a
+    /// queue of replies
+    #[derive(Default)]
+    struct FakeI2c {
+        writes: Vec<Vec<u8>>,
+        failures: usize,
+        replies: Vec<[u8; 11]>,
+    }
+
+    /// What a monitor sends for get VCP 0x10
+    fn brightness_reply(current: u16, max: u16) -> [u8; 11] {
+        let [mh, ml] = max.to_be_bytes();
+        let [ch, cl] = current.to_be_bytes();
+        let mut reply = [0x6e, 0x88, 0x02, 0x00, 0x10, 0x00, mh, ml, ch, cl, 0];
+        reply[10] = checksum(REPLY_ADDRESS, &reply[..10]);
+        reply
+    }
+
+    impl I2c for FakeI2c {
+        fn write(&mut self, data: &[u8]) -> io::Result<()> {
+            self.writes.push(data.to_vec());
+            if self.failures > 0 {
+                self.failures -= 1;
+                return Err(io::Error::from_raw_os_error(libc::EREMOTEIO));
+            }
+            Ok(())
+        }
+        fn read(&mut self, data: &mut [u8]) -> io::Result<()> {
+            if self.replies.is_empty() {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+            data.copy_from_slice(&self.replies.remove(0));
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_set_vcp_message() {
+        let mut bus = FakeI2c::default();
+        set_vcp(&mut bus, VCP_BRIGHTNESS, 50).unwrap();
+        assert_eq!(
+            bus.writes,
+            vec![vec![0x51, 0x84, 0x03, 0x10, 0x00, 0x32, 0x9a]]
+        );
+    }
+    #[test]
+    fn test_set_vcp_retries() {
+        let mut bus = FakeI2c {
+            failures: 2,
+            ..Default::default()
+        };
+        set_vcp(&mut bus, VCP_BRIGHTNESS, 0x1234).unwrap();
+        assert_eq!(bus.writes.len(), 3);
+        assert_eq!(
+            bus.writes[2],
+            vec![0x51, 0x84, 0x03, 0x10, 0x12, 0x34, 0x8e]
+        );
+        let mut bus = FakeI2c {
+            failures: 3,
+            ..Default::default()
+        };
+        assert!(set_vcp(&mut bus, VCP_BRIGHTNESS, 10).is_err());
+    }
+    #[test]
+    fn test_get_vcp() {
+        let mut garbled = brightness_reply(30, 100);
+        garbled[8] = 0xff;
+        let mut bus = FakeI2c {
+            replies: vec![garbled, brightness_reply(30, 100)],
+            ..Default::default()
+        };
+        let v = get_vcp(&mut bus, VCP_BRIGHTNESS).unwrap();
+        assert_eq!(v, VcpValue { current: 30, max: 100 });
+        // retried after the bad checksum
+        assert_eq!(bus.writes.len(), 2);
+        assert_eq!(bus.writes[0], vec![0x51, 0x82, 0x01, 0x10, 0xac]);
+        let mut unsupported = brightness_reply(0, 0);
+        unsupported[3] = 0x01;
+        unsupported[10] = checksum(REPLY_ADDRESS, &unsupported[..10]);
+        let mut bus = FakeI2c {
+            replies: vec![unsupported; 3],
+            ..Default::default()
+        };
+        assert!(get_vcp(&mut bus, VCP_BRIGHTNESS).is_err());
+    }
+}
@@ -1,25 +1,42 @@
+use std::ffi::OsString;
 use std::path::PathBuf;
 
 #[derive(Debug)]
 pub enum Error {
     BadPath(PathBuf),
-    IOError(std::io::Error),
+    Io(std::io::Error),
     BadParse,
+    BadJson(String),
     MaxBrightnessRequired,
     NoBacklightStatus,
     BadConfiguration(&'static str),
     NoConfigFile,
+    NoSuchDisplay(OsString),
+    NoSocketPath,
+    CannotBatch(String),
 }
 
 impl std::error::Error for Error {}
 
 impl std::fmt::Display for Error {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "ERROR")
+        match self {
+            Error::BadPath(p) => write!(f, "bad path {p:?}"),
+            Error::Io(e) => write!(f, "i/o error: {e}"),
+            Error::BadParse => write!(f, "could not parse"),
+            Error::BadJson(msg) => write!(f, "could not parse JSON: {msg}"),
+            Error::MaxBrightnessRequired => write!(f, "maximum brightness is required"),
+            Error::NoBacklightStatus => write!(f, "backlight status is not available"),
+            Error::BadConfiguration(msg) => write!(f, "bad configuration: {msg}"),
+            Error::NoConfigFile => write!(f, "no configuration file found"),
+            Error::NoSuchDisplay(name) => write!(f, "no display named {name:?}"),
+            Error::CannotBatch(cmd) => write!(f, "command cannot be part of a batch: {cmd}"),
+            Error::NoSocketPath => write!(f, "no socket path given and XDG_RUNTIME_DIR is not set"),
+        }
     }
 }
 impl From<std::io::Error> for Error {
     fn from(value: std::io::Error) -> Self {
-        Self::IOError(value)
+        Self::Io(value)
     }
 }
@@ -0,0 +1,88 @@
+use backlightd::{BacklightEvent, DisplayStatus};
+use std::sync::mpsc::Sender;
+
+/// Clients that asked to be told about changes, and the state they were last
+/// told about
+#[derive(Default)]
+pub struct Subscribers {
+    senders: Vec<Sender<BacklightEvent>>,
+    last: Vec<DisplayStatus>,
+}
+
+impl Subscribers {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.senders.is_empty()
+    }
+    /// The new subscriber has been sent current, so later events are
+    /// relative to it
+    pub fn add(&mut self, sender: Sender<BacklightEvent>, current: Vec<DisplayStatus>) {
+        self.senders.push(sender);
+        self.last = current;
+    }
+    /// Sends an event for every display that changed since the last call.
+    /// Subscribers that have gone away are dropped.
+    pub fn publish(&mut self, current: Vec<DisplayStatus>, reloaded: bool) {
+        let mut events = Vec::new();
+        if reloaded {
+            events.push(BacklightEvent::Reloaded);
+        }
+        for d in &current {
+            if !self.last.iter().any(|old| is_same_state(old, d)) {
+                events.push(BacklightEvent::Changed(d.clone()));
+            }
+        }
+        self.last = current;
+        if events.is_empty() {
+            return;
+        }
+        self.senders
+            .retain(|s| events.iter().all(|e| s.send(e.clone()).is_ok()));
+    }
+}
+
+fn is_same_state(a: &DisplayStatus, b: &DisplayStatus) -> bool {
+    a.name == b.name && a.level == b.level && a.value == b.value && a.power == b.power
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use backlightd::{ClampState, PowerState};
+    use std::sync::mpsc;
+
+    fn status(name: &str, level: f32) -> DisplayStatus {
+        DisplayStatus {
+            name: name.into(),
+            level,
+            percent: Some(50.0),
+            value: 100,
+            clamp: ClampState::Intermediate,
+            power: PowerState::On,
+            brightness_control: None,
+            onoff_control: None,
+        }
+    }
+
+    #[test]
+    fn test_publish_changes_only() {
+        let mut subs = Subscribers::new();
+        let (tx, rx) = mpsc::channel();
+        subs.add(tx, vec![status("laptop", 4.0), status("desktop", 4.0)]);
+        subs.publish(vec![status("laptop", 3.0), status("desktop", 4.0)], false);
+        subs.publish(vec![status("laptop", 3.0), status("desktop", 4.0)], true);
+        let events: Vec<_> = rx.try_iter().collect();
+        assert_eq!(
+            events,
+            vec![
+                BacklightEvent::Changed(status("laptop", 3.0)),
+                BacklightEvent::Reloaded,
+            ]
+        );
+        drop(rx);
+        subs.publish(vec![status("laptop", 2.0)], false);
+        assert!(subs.is_empty());
+    }
+}
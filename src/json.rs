@@ -0,0 +1,348 @@
+//! JSON encoding of the control protocol, for clients that would rather not
+//! speak the text grammar. A request is an object tagged by "command":
+//!
+//! ```text
+//! {"command": "set", "display": "DP 3", "level": "40%"}
+//! ```
+//!
+//! and gets a reply like {"ok": true, "displays": [...]} or
+//! {"ok": false, "error": "..."}. Display names are strings, "all" selects
+//! every display. Levels are numbers, or strings like "40%".
+use crate::error::Error;
+use crate::*;
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "command", rename_all = "snake_case", deny_unknown_fields)]
+enum JsonCommand {
+    Swaysock {
+        path: PathBuf,
+    },
+    On {
+        display: String,
+    },
+    Off {
+        display: String,
+    },
+    Up {
+        display: String,
+        #[serde(default = "one_step")]
+        steps: f32,
+    },
+    Down {
+        display: String,
+        #[serde(default = "one_step")]
+        steps: f32,
+    },
+    FineUp {
+        display: String,
+    },
+    FineDown {
+        display: String,
+    },
+    Set {
+        display: String,
+        level: JsonLevel,
+    },
+    Toggle {
+        display: String,
+    },
+    Max {
+        display: String,
+    },
+    Min {
+        display: String,
+    },
+    Default {
+        display: String,
+    },
+    Status {
+        #[serde(default = "all_displays")]
+        display: String,
+    },
+    List,
+    Reload,
+    Sync {
+        display: String,
+    },
+    Subscribe,
+    Batch {
+        commands: Vec<JsonCommand>,
+    },
+}
+
+fn one_step() -> f32 {
+    1.0
+}
+
+fn all_displays() -> String {
+    TargetDisplay::All.to_string()
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(untagged)]
+enum JsonLevel {
+    Number(f32),
+    Text(String),
+}
+
+fn target(display: String) -> TargetDisplay {
+    // infallible
+    display.parse().unwrap()
+}
+
+impl TryFrom<JsonCommand> for BacklightCommand {
+    type Error = Error;
+
+    fn try_from(value: JsonCommand) -> Result<Self, Error> {
+        let cmd = match value {
+            JsonCommand::Swaysock { path } => BacklightCommand::SwaySock(path),
+            JsonCommand::On { display } => BacklightCommand::On(target(display)),
+            JsonCommand::Off { display } => BacklightCommand::Off(target(display)),
+            JsonCommand::Up { display, steps } => BacklightCommand::Up(target(display), steps),
+            JsonCommand::Down { display, steps } => BacklightCommand::Down(target(display), steps),
+            JsonCommand::FineUp { display } => BacklightCommand::FineUp(target(display)),
+            JsonCommand::FineDown { display } => BacklightCommand::FineDown(target(display)),
+            JsonCommand::Set { display, level } => {
+                let level = match level {
+                    JsonLevel::Number(l) => Level::Absolute(l),
+                    JsonLevel::Text(s) => s.parse()?,
+                };
+                BacklightCommand::Set(target(display), level)
+            }
+            JsonCommand::Toggle { display } => BacklightCommand::Toggle(target(display)),
+            JsonCommand::Max { display } => BacklightCommand::Max(target(display)),
+            JsonCommand::Min { display } => BacklightCommand::Min(target(display)),
+            JsonCommand::Default { display } => BacklightCommand::Default(target(display)),
+            JsonCommand::Status { display } => BacklightCommand::Status(target(display)),
+            JsonCommand::List => BacklightCommand::List,
+            JsonCommand::Reload => BacklightCommand::Reload,
+            JsonCommand::Sync { display } => BacklightCommand::Sync(target(display)),
+            JsonCommand::Subscribe => BacklightCommand::Subscribe,
+            JsonCommand::Batch { commands } => {
+                let cmds = commands
+                    .into_iter()
+                    .map(BacklightCommand::try_from)
+                    .collect::<Result<Vec<_>, _>>()?;
+                if let Some(cmd) = cmds.iter().find(|c| !c.can_batch()) {
+                    return Err(Error::CannotBatch(cmd.to_string()));
+                }
+                BacklightCommand::Batch(cmds)
+            }
+        };
+        Ok(cmd)
+    }
+}
+
+impl From<&BacklightCommand> for JsonCommand {
+    fn from(value: &BacklightCommand) -> Self {
+        let name = |d: &TargetDisplay| d.to_string();
+        match value {
+            BacklightCommand::SwaySock(path) => JsonCommand::Swaysock { path: path.clone() },
+            BacklightCommand::On(d) => JsonCommand::On { display: name(d) },
+            BacklightCommand::Off(d) => JsonCommand::Off { display: name(d) },
+            BacklightCommand::Up(d, steps) => JsonCommand::Up {
+                display: name(d),
+                steps: *steps,
+            },
+            BacklightCommand::Down(d, steps) => JsonCommand::Down {
+                display: name(d),
+                steps: *steps,
+            },
+            BacklightCommand::FineUp(d) => JsonCommand::FineUp { display: name(d) },
+            BacklightCommand::FineDown(d) => JsonCommand::FineDown { display: name(d) },
+            BacklightCommand::Set(d, level) => JsonCommand::Set {
+                display: name(d),
+                level: match level {
+                    Level::Absolute(l) => JsonLevel::Number(*l),
+                    Level::Percent(_) => JsonLevel::Text(level.to_string()),
+                },
+            },
+            BacklightCommand::Toggle(d) => JsonCommand::Toggle { display: name(d) },
+            BacklightCommand::Max(d) => JsonCommand::Max { display: name(d) },
+            BacklightCommand::Min(d) => JsonCommand::Min { display: name(d) },
+            BacklightCommand::Default(d) => JsonCommand::Default { display: name(d) },
+            BacklightCommand::Status(d) => JsonCommand::Status { display: name(d) },
+            BacklightCommand::List => JsonCommand::List,
+            BacklightCommand::Reload => JsonCommand::Reload,
+            BacklightCommand::Sync(d) => JsonCommand::Sync { display: name(d) },
+            BacklightCommand::Subscribe => JsonCommand::Subscribe,
+            BacklightCommand::Batch(cmds) => JsonCommand::Batch {
+                commands: cmds.iter().map(JsonCommand::from).collect(),
+            },
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct JsonStatus {
+    name: String,
+    level: f32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    percent: Option<f32>,
+    value: usize,
+    clamp: ClampState,
+    power: PowerState,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    brightness_control: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    onoff_control: Option<String>,
+}
+
+impl From<&DisplayStatus> for JsonStatus {
+    fn from(d: &DisplayStatus) -> Self {
+        Self {
+            name: d.name.to_string_lossy().into_owned(),
+            level: d.level,
+            percent: d.percent,
+            value: d.value,
+            clamp: d.clamp,
+            power: d.power,
+            brightness_control: d.brightness_control.clone(),
+            onoff_control: d.onoff_control.clone(),
+        }
+    }
+}
+
+impl From<JsonStatus> for DisplayStatus {
+    fn from(d: JsonStatus) -> Self {
+        Self {
+            name: d.name.into(),
+            level: d.level,
+            percent: d.percent,
+            value: d.value,
+            clamp: d.clamp,
+            power: d.power,
+            brightness_control: d.brightness_control,
+            onoff_control: d.onoff_control,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct JsonReply {
+    ok: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    displays: Option<Vec<JsonStatus>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+enum JsonEvent {
+    Changed { display: JsonStatus },
+    Reloaded,
+}
+
+fn bad_json(e: serde_json::Error) -> Error {
+    Error::BadJson(e.to_string())
+}
+
+pub fn parse_command(input: &[u8]) -> Result<BacklightCommand, Error> {
+    let cmd: JsonCommand = serde_json::from_slice(input).map_err(bad_json)?;
+    cmd.try_into()
+}
+
+pub fn command_to_string(cmd: &BacklightCommand) -> String {
+    // cannot fail, every field is a string, number or plain struct
+    serde_json::to_string(&JsonCommand::from(cmd)).unwrap()
+}
+
+pub fn parse_reply(input: &[u8]) -> Result<BacklightReply, Error> {
+    let reply: JsonReply = serde_json::from_slice(input).map_err(bad_json)?;
+    Ok(match reply {
+        JsonReply { ok: true, displays, .. } => {
+            let displays = displays.unwrap_or_default().into_iter();
+            BacklightReply::Ok(displays.map(DisplayStatus::from).collect())
+        }
+        JsonReply { error, .. } => BacklightReply::Error(error.unwrap_or_default()),
+    })
+}
+
+pub fn reply_to_string(reply: &BacklightReply) -> String {
+    let reply = match reply {
+        BacklightReply::Ok(displays) => JsonReply {
+            ok: true,
+            displays: Some(displays.iter().map(JsonStatus::from).collect()),
+            error: None,
+        },
+        BacklightReply::Error(msg) => JsonReply {
+            ok: false,
+            displays: None,
+            error: Some(msg.clone()),
+        },
+    };
+    serde_json::to_string(&reply).unwrap()
+}
+
+pub fn parse_event(input: &[u8]) -> Result<BacklightEvent, Error> {
+    let event: JsonEvent = serde_json::from_slice(input).map_err(bad_json)?;
+    Ok(match event {
+        JsonEvent::Changed { display } => BacklightEvent::Changed(display.into()),
+        JsonEvent::Reloaded => BacklightEvent::Reloaded,
+    })
+}
+
+pub fn event_to_string(event: &BacklightEvent) -> String {
+    let event = match event {
+        BacklightEvent::Changed(d) => JsonEvent::Changed { display: d.into() },
+        BacklightEvent::Reloaded => JsonEvent::Reloaded,
+    };
+    serde_json::to_string(&event).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_command_round_trip() {
+        let cmds = [
+            BacklightCommand::Up(TargetDisplay::Display("DP 3".into()), 2.5),
+            BacklightCommand::Set(TargetDisplay::All, Level::Percent(40.0)),
+            BacklightCommand::Set(TargetDisplay::All, Level::Absolute(-1.0)),
+            BacklightCommand::SwaySock("/run/user/1000/sway ipc.sock".into()),
+            BacklightCommand::Batch(vec![
+                BacklightCommand::Max(TargetDisplay::Display("laptop".into())),
+                BacklightCommand::List,
+            ]),
+            BacklightCommand::Subscribe,
+        ];
+        for cmd in cmds {
+            let text = command_to_string(&cmd);
+            assert_eq!(parse_command(text.as_bytes()).unwrap(), cmd);
+        }
+    }
+    #[test]
+    fn test_command_defaults() {
+        let r = parse_command(br#"{"command": "up", "display": "laptop"}"#).unwrap();
+        assert_eq!(r, BacklightCommand::Up(TargetDisplay::Display("laptop".into()), 1.0));
+        let r = parse_command(br#"{"command": "status"}"#).unwrap();
+        assert_eq!(r, BacklightCommand::Status(TargetDisplay::All));
+        assert!(parse_command(br#"{"command": "up"}"#).is_err());
+        assert!(parse_command(br#"{"command": "batch", "commands": [{"command": "reload"}]}"#).is_err());
+    }
+    #[test]
+    fn test_reply_round_trip() {
+        let reply = BacklightReply::Ok(vec![DisplayStatus {
+            name: "DP 3".into(),
+            level: 4.5,
+            percent: Some(50.0),
+            value: 12000,
+            clamp: ClampState::Max,
+            power: PowerState::Unknown,
+            brightness_control: Some("ddcutil:1".into()),
+            onoff_control: None,
+        }]);
+        let text = reply_to_string(&reply);
+        assert_eq!(
+            text,
+            r#"{"ok":true,"displays":[{"name":"DP 3","level":4.5,"percent":50.0,"value":12000,"clamp":"max","power":"unknown","brightness_control":"ddcutil:1"}]}"#
+        );
+        assert_eq!(parse_reply(text.as_bytes()).unwrap(), reply);
+        let error = BacklightReply::Error("no display named \"DP 9\"".into());
+        assert_eq!(parse_reply(reply_to_string(&error).as_bytes()).unwrap(), error);
+        assert_eq!(event_to_string(&BacklightEvent::Reloaded), r#"{"event":"reloaded"}"#);
+    }
+}
@@ -1,45 +1,465 @@
 use std::ffi::{OsStr, OsString};
+use std::fmt;
 use std::os::unix::prelude::OsStrExt;
 use std::path::PathBuf;
+use std::str::FromStr;
+use serde::{Deserialize, Serialize};
 mod error;
+pub mod json;
 
-#[derive(Debug, PartialEq)]
+/// The server socket lives at $XDG_RUNTIME_DIR/backlight unless configured
+/// otherwise
+pub fn default_socket_path() -> Option<PathBuf> {
+    let xdg_runtime_dir = std::env::var_os("XDG_RUNTIME_DIR")?;
+    Some(PathBuf::from(xdg_runtime_dir).join("backlight"))
+}
+
+#[derive(Debug, PartialEq, Clone)]
 pub enum TargetDisplay {
     Display(OsString),
     All,
 }
 
-#[derive(Debug, PartialEq)]
+/// "all" in any case selects every display, like the command parser
+impl FromStr for TargetDisplay {
+    type Err = std::convert::Infallible;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.eq_ignore_ascii_case("all") {
+            Ok(TargetDisplay::All)
+        } else {
+            Ok(TargetDisplay::Display(s.into()))
+        }
+    }
+}
+
+impl fmt::Display for TargetDisplay {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TargetDisplay::Display(name) => write!(f, "{}", name.to_string_lossy()),
+            TargetDisplay::All => f.write_str("all"),
+        }
+    }
+}
+
+/// Target of a set command: a brightness level, or a percentage of the
+/// reference range where 100% is ref_max and 0% is ref_min. Either may be
+/// fractional.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Level {
+    Absolute(f32),
+    Percent(f32),
+}
+
+/// A level is written "3", a percentage "40%"
+impl FromStr for Level {
+    type Err = error::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (r, make): (_, fn(f32) -> Level) = match s.strip_suffix('%') {
+            Some(p) => (p.parse::<f32>(), Level::Percent),
+            None => (s.parse::<f32>(), Level::Absolute),
+        };
+        match r {
+            Ok(x) if x.is_finite() => Ok(make(x)),
+            _ => Err(error::Error::BadParse),
+        }
+    }
+}
+
+impl fmt::Display for Level {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Level::Absolute(l) => write!(f, "{l}"),
+            Level::Percent(p) => write!(f, "{p}%"),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
 pub enum BacklightCommand {
     SwaySock(PathBuf),
     On(TargetDisplay),
     Off(TargetDisplay),
-    Up(TargetDisplay),
-    Down(TargetDisplay),
+    /// Brighter by a number of levels, "up DP-3 2". One level if omitted, and
+    /// may be fractional.
+    Up(TargetDisplay, f32),
+    Down(TargetDisplay, f32),
+    /// Brighter by the configured fine step, "fine up DP-3"
+    FineUp(TargetDisplay),
+    FineDown(TargetDisplay),
+    Set(TargetDisplay, Level),
     Toggle(TargetDisplay),
     Max(TargetDisplay),
     Min(TargetDisplay),
     Default(TargetDisplay),
+    /// Read-only, "get" is accepted as a synonym
+    Status(TargetDisplay),
+    /// Read-only, reports every configured display
+    List,
+    /// Re-read the config file, keeping the current levels
+    Reload,
+    /// Read the brightness back from displays that support it, and move
+    /// their levels to match, e.g. after the monitor's own buttons were used
+    Sync(TargetDisplay),
+    /// Commands separated by ';' on one line, "set laptop 2; set desktop 5".
+    /// Either all of them take effect or none does.
+    Batch(Vec<BacklightCommand>),
+    /// Reply with the state of every display, then keep the connection open
+    /// and send a BacklightEvent line for every change
+    Subscribe,
+}
+
+impl BacklightCommand {
+    /// Commands that may be part of a batch: those that only change or
+    /// report brightness levels, which can be rolled back
+    pub fn can_batch(&self) -> bool {
+        use BacklightCommand::*;
+        matches!(
+            self,
+            Up(..) | Down(..) | FineUp(_) | FineDown(_) | Set(..) | Max(_) | Min(_)
+                | Default(_) | Status(_) | List
+        )
+    }
+    /// Commands that may change brightness levels, as opposed to power or
+    /// queries
+    pub fn changes_brightness(&self) -> bool {
+        use BacklightCommand::*;
+        match self {
+            Up(..) | Down(..) | FineUp(_) | FineDown(_) | Set(..) | Max(_) | Min(_) | Default(_) => {
+                true
+            }
+            Batch(cmds) => cmds.iter().any(|c| c.changes_brightness()),
+            _ => false,
+        }
+    }
+    /// False if a display name has a space, ';' or newline in it, or a path
+    /// has ';' or a newline, which the text grammar would read as separators
+    pub fn fits_text(&self) -> bool {
+        use BacklightCommand::*;
+        let fits = |s: &OsStr, seps: &[u8]| !s.as_bytes().iter().any(|c| seps.contains(c));
+        match self {
+            SwaySock(p) => fits(p.as_os_str(), b";\n"),
+            Batch(cmds) => cmds.iter().all(|c| c.fits_text()),
+            On(d) | Off(d) | Up(d, _) | Down(d, _) | FineUp(d) | FineDown(d) | Set(d, _)
+            | Toggle(d) | Max(d) | Min(d) | Default(d) | Status(d) | Sync(d) => match d {
+                TargetDisplay::Display(name) => fits(name, b" ;\n"),
+                TargetDisplay::All => true,
+            },
+            List | Reload | Subscribe => true,
+        }
+    }
+}
+
+/// Each line on the socket is either in the text grammar or, if it starts
+/// with '{', JSON. Replies and events use the encoding of the request.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum WireFormat {
+    Text,
+    Json,
+}
+
+impl WireFormat {
+    pub fn detect(line: &[u8]) -> Self {
+        match line.trim_ascii_start().first() {
+            Some(b'{') => WireFormat::Json,
+            _ => WireFormat::Text,
+        }
+    }
+    pub fn parse_command(&self, line: &[u8]) -> Result<BacklightCommand, error::Error> {
+        match self {
+            WireFormat::Text => BacklightCommand::try_from(line),
+            WireFormat::Json => json::parse_command(line),
+        }
+    }
+    /// The format to send cmd in, self unless cmd does not fit in text
+    pub fn for_command(&self, cmd: &BacklightCommand) -> Self {
+        if cmd.fits_text() {
+            *self
+        } else {
+            WireFormat::Json
+        }
+    }
+    pub fn command(&self, cmd: &BacklightCommand) -> String {
+        match self {
+            WireFormat::Text => cmd.to_string(),
+            WireFormat::Json => json::command_to_string(cmd),
+        }
+    }
+    pub fn parse_reply(&self, line: &[u8]) -> Result<BacklightReply, error::Error> {
+        match self {
+            WireFormat::Text => BacklightReply::try_from(line),
+            WireFormat::Json => json::parse_reply(line),
+        }
+    }
+    pub fn reply(&self, reply: &BacklightReply) -> String {
+        match self {
+            WireFormat::Text => reply.to_string(),
+            WireFormat::Json => json::reply_to_string(reply),
+        }
+    }
+    pub fn parse_event(&self, line: &[u8]) -> Result<BacklightEvent, error::Error> {
+        match self {
+            WireFormat::Text => BacklightEvent::try_from(line),
+            WireFormat::Json => json::parse_event(line),
+        }
+    }
+    pub fn event(&self, event: &BacklightEvent) -> String {
+        match self {
+            WireFormat::Text => event.to_string(),
+            WireFormat::Json => json::event_to_string(event),
+        }
+    }
 }
 
-/// Backlight commands are sent in verb-noun order: "on DP-3"
+/// Backlight commands are sent in verb-noun order: "on DP-3". Several
+/// commands separated by ';' make a batch.
 impl TryFrom<&[u8]> for BacklightCommand {
     type Error = error::Error;
 
     fn try_from(value: &[u8]) -> Result<Self, Self::Error> {
-        use parsing::parse_command;
-        parse_command(value).map_err(|_| error::Error::BadParse)
+        use parsing::parse_batch;
+        parse_batch(value).map_err(|_| error::Error::BadParse)
+    }
+}
+
+/// Where a brightness value sits relative to the limits of its display
+#[derive(Debug, PartialEq, Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ClampState {
+    Min,
+    Max,
+    Intermediate,
+}
+
+impl ClampState {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ClampState::Min => "min",
+            ClampState::Max => "max",
+            ClampState::Intermediate => "intermediate",
+        }
+    }
+}
+
+impl fmt::Display for ClampState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// On/off state of a display. Unknown when the onoff control cannot be read
+/// back, as with swaydpms.
+#[derive(Debug, PartialEq, Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PowerState {
+    On,
+    Off,
+    Unknown,
+}
+
+impl PowerState {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            PowerState::On => "on",
+            PowerState::Off => "off",
+            PowerState::Unknown => "unknown",
+        }
+    }
+}
+
+impl fmt::Display for PowerState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// State of a single display, as reported back to the client
+#[derive(Debug, PartialEq, Clone)]
+pub struct DisplayStatus {
+    pub name: OsString,
+    pub level: f32,
+    /// The level as a percentage of the reference range, 100 being ref_max.
+    /// Outside 0-100 when the level is beyond the reference range. None in
+    /// replies from daemons that do not report it.
+    pub percent: Option<f32>,
+    pub value: usize,
+    pub clamp: ClampState,
+    pub power: PowerState,
+    /// Control methods in config file syntax, e.g. "ddcutil:1"
+    pub brightness_control: Option<String>,
+    pub onoff_control: Option<String>,
+}
+
+/// A field value, written in double quotes if it has spaces, '=' or ';' in
+/// it, or is empty. Inside the quotes '"' and '\' are escaped with '\', and
+/// a newline is written "\n".
+struct FieldValue<'a>(&'a str);
+
+impl fmt::Display for FieldValue<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let needs_quotes = |c: char| c.is_whitespace() || matches!(c, '=' | ';' | '"' | '\\');
+        if !self.0.is_empty() && !self.0.contains(needs_quotes) {
+            return f.write_str(self.0);
+        }
+        f.write_str("\"")?;
+        for c in self.0.chars() {
+            match c {
+                '"' | '\\' => write!(f, "\\{c}")?,
+                '\n' => f.write_str("\\n")?,
+                c => write!(f, "{c}")?,
+            }
+        }
+        f.write_str("\"")
+    }
+}
+
+/// Reported as space separated key=value fields: "name=DP-3 level=4 ...".
+/// Control methods that are not configured, and an unknown percent, are left
+/// out. Values are quoted as FieldValue describes.
+impl fmt::Display for DisplayStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = self.name.to_string_lossy();
+        write!(f, "name={} level={}", FieldValue(&name), self.level)?;
+        if let Some(percent) = self.percent {
+            write!(f, " percent={percent}")?;
+        }
+        write!(f, " value={} clamp={} power={}", self.value, self.clamp, self.power)?;
+        if let Some(ref c) = self.brightness_control {
+            write!(f, " brightness_control={}", FieldValue(c))?;
+        }
+        if let Some(ref c) = self.onoff_control {
+            write!(f, " onoff_control={}", FieldValue(c))?;
+        }
+        Ok(())
+    }
+}
+
+/// Every command gets a reply of exactly one line, either "ok" followed by
+/// the affected displays separated by ';', or "error" followed by a message.
+#[derive(Debug, PartialEq)]
+pub enum BacklightReply {
+    Ok(Vec<DisplayStatus>),
+    Error(String),
+}
+
+impl BacklightReply {
+    pub fn is_ok(&self) -> bool {
+        matches!(self, Self::Ok(_))
+    }
+}
+
+impl<E: fmt::Display> From<Result<Vec<DisplayStatus>, E>> for BacklightReply {
+    fn from(value: Result<Vec<DisplayStatus>, E>) -> Self {
+        match value {
+            Ok(displays) => Self::Ok(displays),
+            // keep the reply on a single line
+            Err(e) => Self::Error(e.to_string().replace('\n', " ")),
+        }
+    }
+}
+
+impl fmt::Display for BacklightReply {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Ok(displays) => {
+                f.write_str("ok")?;
+                for (i, d) in displays.iter().enumerate() {
+                    if i > 0 {
+                        f.write_str(";")?;
+                    }
+                    write!(f, " {d}")?;
+                }
+                Ok(())
+            }
+            Self::Error(msg) => write!(f, "error {msg}"),
+        }
+    }
+}
+
+/// Sent to subscribers, one per line. "changed" carries the new state of a
+/// display in the same fields as a reply, e.g. "changed name=DP-3 level=4 ...".
+/// "reloaded" means the config was re-read and displays may have been added
+/// or removed.
+#[derive(Debug, PartialEq, Clone)]
+pub enum BacklightEvent {
+    Changed(DisplayStatus),
+    Reloaded,
+}
+
+impl fmt::Display for BacklightEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Changed(d) => write!(f, "changed {d}"),
+            Self::Reloaded => f.write_str("reloaded"),
+        }
+    }
+}
+
+impl TryFrom<&[u8]> for BacklightEvent {
+    type Error = error::Error;
+
+    fn try_from(value: &[u8]) -> Result<Self, error::Error> {
+        use parsing::parse_event;
+        parse_event(value).map_err(|_| error::Error::BadParse)
+    }
+}
+
+impl TryFrom<&[u8]> for BacklightReply {
+    type Error = error::Error;
+
+    fn try_from(value: &[u8]) -> Result<Self, error::Error> {
+        use parsing::parse_reply;
+        parse_reply(value).map_err(|_| error::Error::BadParse)
+    }
+}
+
+/// Serializes to the text accepted by the parser, e.g. "on DP-3"
+impl fmt::Display for BacklightCommand {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BacklightCommand::SwaySock(p) => write!(f, "swaysock {}", p.display()),
+            BacklightCommand::On(d) => write!(f, "on {d}"),
+            BacklightCommand::Off(d) => write!(f, "off {d}"),
+            BacklightCommand::Up(d, steps) => write!(f, "up {d} {steps}"),
+            BacklightCommand::Down(d, steps) => write!(f, "down {d} {steps}"),
+            BacklightCommand::FineUp(d) => write!(f, "fine up {d}"),
+            BacklightCommand::FineDown(d) => write!(f, "fine down {d}"),
+            BacklightCommand::Set(d, level) => write!(f, "set {d} {level}"),
+            BacklightCommand::Toggle(d) => write!(f, "toggle {d}"),
+            BacklightCommand::Max(d) => write!(f, "max {d}"),
+            BacklightCommand::Min(d) => write!(f, "min {d}"),
+            BacklightCommand::Default(d) => write!(f, "default {d}"),
+            BacklightCommand::Status(d) => write!(f, "status {d}"),
+            BacklightCommand::List => f.write_str("list"),
+            BacklightCommand::Reload => f.write_str("reload"),
+            BacklightCommand::Sync(d) => write!(f, "sync {d}"),
+            BacklightCommand::Subscribe => f.write_str("subscribe"),
+            BacklightCommand::Batch(cmds) => {
+                for (i, cmd) in cmds.iter().enumerate() {
+                    if i > 0 {
+                        f.write_str("; ")?;
+                    }
+                    write!(f, "{cmd}")?;
+                }
+                Ok(())
+            }
+        }
     }
 }
 
 mod parsing {
     use crate::*;
+    use std::borrow::Cow;
     // use std::{ffi::OsStr, os::unix::prelude::OsStrExt};
     use nom::{
         branch::alt,
-        bytes::complete::{tag_no_case, take_till, take_while},
-        combinator::{map, rest},
-        sequence::separated_pair,
+        bytes::complete::{tag, tag_no_case, take_till, take_till1, take_while, take_while1},
+        character::complete::char,
+        combinator::{all_consuming, map, map_res, opt, rest, verify},
+        number::complete::float,
+        multi::{separated_list0, separated_list1},
+        sequence::{preceded, separated_pair, terminated, tuple},
     };
     type ParseResult<'a, T> = nom::IResult<&'a [u8], T>;
 
@@ -51,6 +471,10 @@ mod parsing {
         map(take_while(is_space), |_| ())(input)
     }
 
+    fn space1(input: &[u8]) -> nom::IResult<&[u8], ()> {
+        map(take_while1(is_space), |_| ())(input)
+    }
+
     fn not_space(input: &[u8]) -> nom::IResult<&[u8], &[u8]> {
         take_till(is_space)(input)
     }
@@ -64,89 +488,308 @@ mod parsing {
     //    terminated(tag_no_case(t), space0)(input)
     // }
 
-    fn all_displays(input: &[u8]) -> ParseResult<TargetDisplay> {
-        let p = tag_no_case("all");
+    fn all_displays(input: &[u8]) -> ParseResult<'_, TargetDisplay> {
+        let p = verify(token, |t: &[u8]| t.eq_ignore_ascii_case(b"all"));
         map(p, |_| TargetDisplay::All)(input)
     }
 
-    fn specific_display(input: &[u8]) -> ParseResult<TargetDisplay> {
+    fn specific_display(input: &[u8]) -> ParseResult<'_, TargetDisplay> {
         map(token, |t| TargetDisplay::Display(t.to_os_string()))(input)
     }
 
-    fn display(input: &[u8]) -> ParseResult<TargetDisplay> {
+    fn display(input: &[u8]) -> ParseResult<'_, TargetDisplay> {
         alt((all_displays, specific_display))(input)
     }
 
     // Consumes entire input as a PathBuf
-    fn path(input: &[u8]) -> ParseResult<PathBuf> {
+    fn path(input: &[u8]) -> ParseResult<'_, PathBuf> {
         let z = input.to_os_string();
         let p = PathBuf::from(z);
         Ok((&[], p))
     }
 
-    fn on_command(input: &[u8]) -> ParseResult<BacklightCommand> {
+    fn on_command(input: &[u8]) -> ParseResult<'_, BacklightCommand> {
         let p = separated_pair(tag_no_case("on"), space0, display);
         map(p, |(_, d)| BacklightCommand::On(d))(input)
     }
 
-    fn off_command(input: &[u8]) -> ParseResult<BacklightCommand> {
+    fn off_command(input: &[u8]) -> ParseResult<'_, BacklightCommand> {
         let p = separated_pair(tag_no_case("off"), space0, display);
         map(p, |(_, d)| BacklightCommand::Off(d))(input)
     }
 
-    fn up_command(input: &[u8]) -> ParseResult<BacklightCommand> {
-        let p = separated_pair(tag_no_case("up"), space0, display);
-        map(p, |(_, d)| BacklightCommand::Up(d))(input)
+    // nom accepts "inf" and "nan" as floats, which are no use as levels
+    fn finite_float(input: &[u8]) -> ParseResult<'_, f32> {
+        verify(float, |x: &f32| x.is_finite())(input)
+    }
+
+    // Optional number of levels to step, defaults to one. A negative number
+    // is an error, not a missing one.
+    fn steps(input: &[u8]) -> ParseResult<'_, f32> {
+        let n = opt(preceded(space1, finite_float));
+        let n = verify(n, |n: &Option<f32>| n.is_none_or(|x| x >= 0.0));
+        map(n, |n| n.unwrap_or(1.0))(input)
+    }
+
+    fn level(input: &[u8]) -> ParseResult<'_, Level> {
+        let percent = map(terminated(finite_float, char('%')), Level::Percent);
+        let absolute = map(finite_float, Level::Absolute);
+        alt((percent, absolute))(input)
+    }
+
+    fn up_command(input: &[u8]) -> ParseResult<'_, BacklightCommand> {
+        let p = tuple((tag_no_case("up"), space0, display, steps));
+        map(p, |(_, _, d, n)| BacklightCommand::Up(d, n))(input)
+    }
+
+    fn down_command(input: &[u8]) -> ParseResult<'_, BacklightCommand> {
+        let p = tuple((tag_no_case("down"), space0, display, steps));
+        map(p, |(_, _, d, n)| BacklightCommand::Down(d, n))(input)
+    }
+
+    fn fine_up_command(input: &[u8]) -> ParseResult<'_, BacklightCommand> {
+        let verb = tuple((tag_no_case("fine"), space1, tag_no_case("up")));
+        let p = separated_pair(verb, space0, display);
+        map(p, |(_, d)| BacklightCommand::FineUp(d))(input)
     }
 
-    fn down_command(input: &[u8]) -> ParseResult<BacklightCommand> {
-        let p = separated_pair(tag_no_case("down"), space0, display);
-        map(p, |(_, d)| BacklightCommand::Down(d))(input)
+    fn fine_down_command(input: &[u8]) -> ParseResult<'_, BacklightCommand> {
+        let verb = tuple((tag_no_case("fine"), space1, tag_no_case("down")));
+        let p = separated_pair(verb, space0, display);
+        map(p, |(_, d)| BacklightCommand::FineDown(d))(input)
     }
 
-    fn toggle_command(input: &[u8]) -> ParseResult<BacklightCommand> {
+    fn set_command(input: &[u8]) -> ParseResult<'_, BacklightCommand> {
+        let p = tuple((tag_no_case("set"), space0, display, space1, level));
+        map(p, |(_, _, d, _, l)| BacklightCommand::Set(d, l))(input)
+    }
+
+    fn toggle_command(input: &[u8]) -> ParseResult<'_, BacklightCommand> {
         let p = separated_pair(tag_no_case("toggle"), space0, display);
         map(p, |(_, d)| BacklightCommand::Toggle(d))(input)
     }
 
-    fn swaysock_command(input: &[u8]) -> ParseResult<BacklightCommand> {
+    fn swaysock_command(input: &[u8]) -> ParseResult<'_, BacklightCommand> {
         let p = separated_pair(tag_no_case("swaysock"), space0, path);
         map(p, |(_, d)| BacklightCommand::SwaySock(d))(input)
     }
 
-    fn max_command(input: &[u8]) -> ParseResult<BacklightCommand> {
+    fn max_command(input: &[u8]) -> ParseResult<'_, BacklightCommand> {
         let p = separated_pair(tag_no_case("max"), space0, display);
         map(p, |(_, d)| BacklightCommand::Max(d))(input)
     }
 
-    fn min_command(input: &[u8]) -> ParseResult<BacklightCommand> {
+    fn min_command(input: &[u8]) -> ParseResult<'_, BacklightCommand> {
         let p = separated_pair(tag_no_case("min"), space0, display);
         map(p, |(_, d)| BacklightCommand::Min(d))(input)
     }
 
-    fn reference_command(input: &[u8]) -> ParseResult<BacklightCommand> {
+    fn reference_command(input: &[u8]) -> ParseResult<'_, BacklightCommand> {
         let p = separated_pair(tag_no_case("default"), space0, display);
         map(p, |(_, d)| BacklightCommand::Default(d))(input)
     }
 
+    fn status_command(input: &[u8]) -> ParseResult<'_, BacklightCommand> {
+        let verb = alt((tag_no_case("status"), tag_no_case("get")));
+        let p = separated_pair(verb, space0, display);
+        map(p, |(_, d)| BacklightCommand::Status(d))(input)
+    }
+
+    fn list_command(input: &[u8]) -> ParseResult<'_, BacklightCommand> {
+        map(tag_no_case("list"), |_| BacklightCommand::List)(input)
+    }
+
+    fn sync_command(input: &[u8]) -> ParseResult<'_, BacklightCommand> {
+        let p = separated_pair(tag_no_case("sync"), space0, display);
+        map(p, |(_, d)| BacklightCommand::Sync(d))(input)
+    }
+
+    fn reload_command(input: &[u8]) -> ParseResult<'_, BacklightCommand> {
+        map(tag_no_case("reload"), |_| BacklightCommand::Reload)(input)
+    }
+
+    fn subscribe_command(input: &[u8]) -> ParseResult<'_, BacklightCommand> {
+        map(tag_no_case("subscribe"), |_| BacklightCommand::Subscribe)(input)
+    }
+
+    // Anything left over after a command makes it an error
     pub fn parse_command(input: &[u8]) -> Result<BacklightCommand, ()> {
-        let x = alt((
+        let x = all_consuming(alt((
             swaysock_command,
             toggle_command,
             down_command,
             up_command,
+            fine_up_command,
+            fine_down_command,
+            set_command,
             off_command,
             on_command,
             max_command,
             min_command,
             reference_command,
-        ))(input);
+            status_command,
+            list_command,
+            reload_command,
+            subscribe_command,
+            sync_command,
+        )))(input.trim_ascii());
         match x {
             Ok((_, y)) => Ok(y),
             Err(_) => Err(()),
         }
     }
 
+    // Batches are split before parsing, as display names and paths are
+    // otherwise taken up to the next space or the end of input
+    pub fn parse_batch(input: &[u8]) -> Result<BacklightCommand, ()> {
+        let parts: Vec<&[u8]> = input.split(|c| *c == b';').map(|p| p.trim_ascii()).collect();
+        if parts.len() == 1 {
+            return parse_command(parts[0]);
+        }
+        let cmds = parts
+            .into_iter()
+            .map(parse_command)
+            .collect::<Result<Vec<_>, ()>>()?;
+        if cmds.iter().all(|c| c.can_batch()) {
+            Ok(BacklightCommand::Batch(cmds))
+        } else {
+            Err(())
+        }
+    }
+
+    fn is_field_end(c: u8) -> bool {
+        is_space(c) || c == b';'
+    }
+
+    /// The inverse of FieldValue
+    fn quoted_value(input: &[u8]) -> ParseResult<'_, Vec<u8>> {
+        let (mut input, _) = char('"')(input)?;
+        let mut value = Vec::new();
+        loop {
+            input = match input {
+                [b'"', rest @ ..] => return Ok((rest, value)),
+                [b'\\', b'n', rest @ ..] => {
+                    value.push(b'\n');
+                    rest
+                }
+                [b'\\', c, rest @ ..] | [c, rest @ ..] => {
+                    value.push(*c);
+                    rest
+                }
+                [] => {
+                    let e = nom::error::Error::new(input, nom::error::ErrorKind::Char);
+                    return Err(nom::Err::Error(e));
+                }
+            };
+        }
+    }
+
+    fn field(input: &[u8]) -> ParseResult<'_, (&[u8], Cow<'_, [u8]>)> {
+        let key = take_till1(|c| c == b'=' || is_field_end(c));
+        let value = alt((
+            map(quoted_value, Cow::Owned),
+            map(take_till(is_field_end), Cow::Borrowed),
+        ));
+        separated_pair(key, char('='), value)(input)
+    }
+
+    fn field_value<T: std::str::FromStr>(value: &[u8]) -> Result<T, ()> {
+        let s = std::str::from_utf8(value).map_err(|_| ())?;
+        s.parse().map_err(|_| ())
+    }
+
+    fn clamp_state(value: &[u8]) -> Result<ClampState, ()> {
+        match value {
+            b"min" => Ok(ClampState::Min),
+            b"max" => Ok(ClampState::Max),
+            b"intermediate" => Ok(ClampState::Intermediate),
+            _ => Err(()),
+        }
+    }
+
+    fn power_state(value: &[u8]) -> Result<PowerState, ()> {
+        match value {
+            b"on" => Ok(PowerState::On),
+            b"off" => Ok(PowerState::Off),
+            b"unknown" => Ok(PowerState::Unknown),
+            _ => Err(()),
+        }
+    }
+
+    // Unknown keys are skipped, so that older clients can read newer replies
+    fn status_from_fields(fields: Vec<(&[u8], Cow<'_, [u8]>)>) -> Result<DisplayStatus, ()> {
+        let mut name = None;
+        let mut level = None;
+        let mut percent = None;
+        let mut value = None;
+        let mut clamp = None;
+        let mut power = PowerState::Unknown;
+        let mut brightness_control = None;
+        let mut onoff_control = None;
+        for (k, v) in fields {
+            let v = v.as_ref();
+            match k {
+                b"name" => name = Some(v.to_os_string()),
+                b"level" => level = Some(field_value(v)?),
+                b"percent" => percent = Some(field_value(v)?),
+                b"value" => value = Some(field_value(v)?),
+                b"clamp" => clamp = Some(clamp_state(v)?),
+                b"power" => power = power_state(v)?,
+                b"brightness_control" => brightness_control = Some(field_value(v)?),
+                b"onoff_control" => onoff_control = Some(field_value(v)?),
+                _ => (),
+            }
+        }
+        Ok(DisplayStatus {
+            name: name.ok_or(())?,
+            level: level.ok_or(())?,
+            percent,
+            value: value.ok_or(())?,
+            clamp: clamp.ok_or(())?,
+            power,
+            brightness_control,
+            onoff_control,
+        })
+    }
+
+    fn display_status(input: &[u8]) -> ParseResult<'_, DisplayStatus> {
+        map_res(separated_list1(space1, field), status_from_fields)(input)
+    }
+
+    fn ok_reply(input: &[u8]) -> ParseResult<'_, BacklightReply> {
+        let displays = separated_list0(char(';'), preceded(space1, display_status));
+        map(preceded(tag("ok"), displays), BacklightReply::Ok)(input)
+    }
+
+    fn error_reply(input: &[u8]) -> ParseResult<'_, BacklightReply> {
+        let p = preceded(tag("error"), preceded(space0, rest));
+        map(p, |m: &[u8]| {
+            BacklightReply::Error(String::from_utf8_lossy(m).into_owned())
+        })(input)
+    }
+
+    pub fn parse_reply(input: &[u8]) -> Result<BacklightReply, ()> {
+        let input = input.trim_ascii_end();
+        match all_consuming(alt((ok_reply, error_reply)))(input) {
+            Ok((_, y)) => Ok(y),
+            Err(_) => Err(()),
+        }
+    }
+
+    fn changed_event(input: &[u8]) -> ParseResult<'_, BacklightEvent> {
+        let p = preceded(tag("changed"), preceded(space1, display_status));
+        map(p, BacklightEvent::Changed)(input)
+    }
+
+    pub fn parse_event(input: &[u8]) -> Result<BacklightEvent, ()> {
+        let input = input.trim_ascii_end();
+        let reloaded = map(tag("reloaded"), |_| BacklightEvent::Reloaded);
+        match all_consuming(alt((changed_event, reloaded)))(input) {
+            Ok((_, y)) => Ok(y),
+            Err(_) => Err(()),
+        }
+    }
+
     #[cfg(test)]
     mod testing {
         use super::*;
@@ -186,6 +829,197 @@ mod parsing {
             assert_eq!(swaysock_command(input.as_bytes()), r);
         }
         #[test]
+        fn test_up_steps() {
+            let r = ok_result(BacklightCommand::Up(TargetDisplay::All, 1.0));
+            assert_eq!(up_command("up all".as_bytes()), r);
+            let r = ok_result(BacklightCommand::Up(make_disp("DP-3"), 2.0));
+            assert_eq!(up_command("up DP-3 2".as_bytes()), r);
+            let r = ok_result(BacklightCommand::Up(make_disp("DP-3"), 0.5));
+            assert_eq!(up_command("up DP-3 0.5".as_bytes()), r);
+            let r = ok_result(BacklightCommand::FineDown(TargetDisplay::All));
+            assert_eq!(fine_down_command("fine down all".as_bytes()), r);
+            assert!(steps(b" -2").is_err());
+            let r = Ok(BacklightCommand::Up(make_disp("allx"), 1.0));
+            assert_eq!(parse_command(b"up allx"), r);
+            assert!(parse_command(b"up laptop -2").is_err());
+            assert!(parse_command(b"down all 2.5.1").is_err());
+            assert!(parse_command(b"up laptop 2 3").is_err());
+        }
+        #[test]
+        fn test_set() {
+            let r = ok_result(BacklightCommand::Set(make_disp("DP-3"), Level::Absolute(3.0)));
+            assert_eq!(set_command("set DP-3 3".as_bytes()), r);
+            let r = ok_result(BacklightCommand::Set(make_disp("DP-3"), Level::Absolute(4.5)));
+            assert_eq!(set_command("set DP-3 4.5".as_bytes()), r);
+            let r = ok_result(BacklightCommand::Set(TargetDisplay::All, Level::Percent(40.0)));
+            assert_eq!(set_command("set all 40%".as_bytes()), r);
+            assert!(set_command("set all nan".as_bytes()).is_err());
+            assert!(parse_command(b"set laptop 40 %").is_err());
+            assert!(parse_command(b"set laptop 40%%").is_err());
+            assert_eq!(
+                parse_command(b" set laptop 40% "),
+                Ok(BacklightCommand::Set(make_disp("laptop"), Level::Percent(40.0)))
+            );
+        }
+        #[test]
+        fn test_command_round_trip() {
+            let commands = [
+                BacklightCommand::SwaySock("/run/user/1000/sway-ipc.sock".into()),
+                BacklightCommand::On(make_disp("DP-3")),
+                BacklightCommand::Off(TargetDisplay::All),
+                BacklightCommand::Up(make_disp("laptop"), 1.0),
+                BacklightCommand::Down(TargetDisplay::All, 0.25),
+                BacklightCommand::FineUp(make_disp("laptop")),
+                BacklightCommand::FineDown(TargetDisplay::All),
+                BacklightCommand::Set(make_disp("DP-3"), Level::Absolute(-1.0)),
+                BacklightCommand::Set(TargetDisplay::All, Level::Percent(12.5)),
+                BacklightCommand::Toggle(make_disp("DP-3")),
+                BacklightCommand::Max(TargetDisplay::All),
+                BacklightCommand::Min(make_disp("desktop")),
+                BacklightCommand::Default(TargetDisplay::All),
+                BacklightCommand::Status(make_disp("DP-3")),
+                BacklightCommand::List,
+                BacklightCommand::Reload,
+                BacklightCommand::Sync(make_disp("DP-3")),
+                BacklightCommand::Batch(vec![
+                    BacklightCommand::Set(make_disp("laptop"), Level::Absolute(2.0)),
+                    BacklightCommand::Up(make_disp("desktop"), 1.0),
+                ]),
+            ];
+            for cmd in commands {
+                let text = cmd.to_string();
+                assert_eq!(parse_batch(text.as_bytes()), Ok(cmd));
+            }
+        }
+        #[test]
+        fn test_names_with_spaces_go_as_json() {
+            let plain = [
+                BacklightCommand::Set(make_disp("DP-3"), Level::Absolute(4.0)),
+                BacklightCommand::SwaySock("/run/user/1000/sway ipc.sock".into()),
+            ];
+            for cmd in plain {
+                assert_eq!(WireFormat::Text.for_command(&cmd), WireFormat::Text);
+                assert_eq!(parse_batch(cmd.to_string().as_bytes()), Ok(cmd));
+            }
+            let commands = [
+                BacklightCommand::Set(make_disp("DP 3"), Level::Absolute(4.0)),
+                BacklightCommand::Up(make_disp("a;b"), 1.0),
+                BacklightCommand::SwaySock("/run/user/1000/sway;ipc.sock".into()),
+                BacklightCommand::Batch(vec![
+                    BacklightCommand::List,
+                    BacklightCommand::Max(make_disp("DP 3")),
+                ]),
+            ];
+            for cmd in commands {
+                assert_ne!(parse_batch(cmd.to_string().as_bytes()), Ok(cmd.clone()));
+                let format = WireFormat::Text.for_command(&cmd);
+                assert_eq!(format, WireFormat::Json);
+                let line = format.command(&cmd);
+                assert_eq!(format.parse_command(line.as_bytes()).unwrap(), cmd);
+            }
+        }
+        #[test]
+        fn test_batch() {
+            let r = Ok(BacklightCommand::Batch(vec![
+                BacklightCommand::Up(make_disp("laptop"), 1.0),
+                BacklightCommand::Down(TargetDisplay::All, 1.0),
+            ]));
+            assert_eq!(parse_batch("up laptop;down all".as_bytes()), r);
+            assert!(parse_batch("set laptop 2; reload".as_bytes()).is_err());
+            assert!(parse_batch("set laptop 2; bogus".as_bytes()).is_err());
+        }
+        #[test]
+        fn test_status() {
+            let r = ok_result(BacklightCommand::Status(make_disp("DP-3")));
+            assert_eq!(status_command("get DP-3".as_bytes()), r);
+            let r = ok_result(BacklightCommand::Status(TargetDisplay::All));
+            assert_eq!(status_command("status all".as_bytes()), r);
+        }
+        #[test]
+        fn test_reply_round_trip() {
+            let status = |name: &str, clamp| DisplayStatus {
+                name: OsString::from(name),
+                level: 4.5,
+                percent: Some(50.0),
+                value: 12000,
+                clamp,
+                power: PowerState::On,
+                brightness_control: Some("ddcutil:1".into()),
+                onoff_control: None,
+            };
+            let reply = BacklightReply::Ok(vec![
+                status("laptop", ClampState::Intermediate),
+                status("desktop", ClampState::Max),
+            ]);
+            let text = format!("{reply}\n");
+            assert_eq!(
+                text,
+                "ok name=laptop level=4.5 percent=50 value=12000 clamp=intermediate power=on \
+                 brightness_control=ddcutil:1; \
+                 name=desktop level=4.5 percent=50 value=12000 clamp=max power=on \
+                 brightness_control=ddcutil:1\n"
+            );
+            assert_eq!(parse_reply(text.as_bytes()), Ok(reply));
+        }
+        #[test]
+        fn test_quoted_fields() {
+            let status = DisplayStatus {
+                name: OsString::from(r#"DP 3; "left"=main"#),
+                level: 2.0,
+                percent: None,
+                value: 500,
+                clamp: ClampState::Max,
+                power: PowerState::On,
+                brightness_control: Some(r"sysfs:/tmp/my display\brightness".into()),
+                onoff_control: Some(String::new()),
+            };
+            let expected = r#"name="DP 3; \"left\"=main" level=2 value=500 clamp=max power=on brightness_control="sysfs:/tmp/my display\\brightness" onoff_control="""#;
+            assert_eq!(format!("{status}"), expected);
+            let reply = BacklightReply::Ok(vec![status]);
+            let text = format!("{reply}");
+            assert_eq!(parse_reply(text.as_bytes()), Ok(reply));
+            assert!(parse_reply(br#"ok name="DP 3 level=2 value=500 clamp=max"#).is_err());
+        }
+        #[test]
+        fn test_reply_without_percent() {
+            let r = parse_reply(b"ok name=laptop level=2 value=500 clamp=max power=on");
+            let Ok(BacklightReply::Ok(displays)) = r else {
+                panic!("{r:?}");
+            };
+            assert_eq!(displays[0].percent, None);
+            assert_eq!(format!("{}", displays[0]), "name=laptop level=2 value=500 clamp=max power=on");
+        }
+        #[test]
+        fn test_empty_reply() {
+            let r = Ok(BacklightReply::Ok(Vec::new()));
+            assert_eq!(parse_reply("ok\n".as_bytes()), r);
+        }
+        #[test]
+        fn test_error_reply() {
+            let r = Ok(BacklightReply::Error("no display named \"DP-9\"".into()));
+            assert_eq!(parse_reply("error no display named \"DP-9\"".as_bytes()), r);
+        }
+        #[test]
+        fn test_events() {
+            let event = BacklightEvent::Changed(DisplayStatus {
+                name: OsString::from("laptop"),
+                level: 3.0,
+                percent: Some(66.7),
+                value: 500,
+                clamp: ClampState::Intermediate,
+                power: PowerState::Off,
+                brightness_control: None,
+                onoff_control: None,
+            });
+            let text = format!("{event}\n");
+            let expected = "changed name=laptop level=3 percent=66.7 value=500 clamp=intermediate \
+                            power=off\n";
+            assert_eq!(text, expected);
+            assert_eq!(parse_event(text.as_bytes()), Ok(event));
+            assert_eq!(parse_event(b"reloaded\n"), Ok(BacklightEvent::Reloaded));
+            assert_eq!(parse_batch(b"subscribe"), Ok(BacklightCommand::Subscribe));
+        }
+        #[test]
         fn test_parsing() {
             // let input = "DoWn SomeDisplay".as_bytes();
             // let d = make_disp("SomeDisplay");
@@ -0,0 +1,231 @@
+use crate::bus::{Bus, BusConnection};
+use log::{debug, warn};
+use std::{io, time::Duration};
+use zbus::{
+    blocking::Connection,
+    names::OwnedErrorName,
+    zvariant::{OwnedObjectPath, OwnedValue},
+};
+
+const LOGIN1_NAME: &str = "org.freedesktop.login1";
+/// The session of the calling process
+const AUTO_SESSION_PATH: &str = "/org/freedesktop/login1/session/auto";
+const SESSION_INTERFACE: &str = "org.freedesktop.login1.Session";
+/// The user of the calling process, whose display session is used when the
+/// process is in no session itself, as for a systemd user service
+const USER_PATH: &str = "/org/freedesktop/login1/user/self";
+const USER_INTERFACE: &str = "org.freedesktop.login1.User";
+const SET_BRIGHTNESS_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Sets brightness through logind, which lets the user of the active session
+/// write to backlight and LED devices without udev rules or root
+pub struct Session {
+    connection: BusConnection,
+    /// The session that took the last call
+    session: Option<OwnedObjectPath>,
+}
+
+impl Session {
+    pub fn new() -> Self {
+        Self::on_bus(Bus::System)
+    }
+    pub fn with_address(address: impl Into<String>) -> Self {
+        Self::on_bus(Bus::Address(address.into()))
+    }
+    fn on_bus(bus: Bus) -> Self {
+        Self {
+            connection: BusConnection::new(bus, "logind", SET_BRIGHTNESS_TIMEOUT),
+            session: None,
+        }
+    }
+    /// Subsystem is "backlight" or "leds", name the device in it
+    pub fn set_brightness(&mut self, subsystem: &str, name: &str, value: u32) -> io::Result<()> {
+        self.call(subsystem, name, value).map_err(|e| {
+            // the session may have gone as well
+            self.connection.reset();
+            self.session = None;
+            io::Error::other(e)
+        })
+    }
+    fn call(&mut self, subsystem: &str, name: &str, value: u32) -> zbus::Result<()> {
+        let connection = self.connection.get().inspect_err(|e| {
+            warn!("Could not connect to logind: {e}");
+        })?;
+        let args = (subsystem, name, value);
+        if let Some(ref session) = self.session {
+            return set_brightness(connection, session.as_str(), &args);
+        }
+        let session = match set_brightness(connection, AUTO_SESSION_PATH, &args) {
+            Err(zbus::Error::MethodError(ref e, _, _)) if is_no_session(e) => {
+                debug!("Not in a session, using the display session of the user");
+                let session = display_session(connection)?;
+                set_brightness(connection, session.as_str(), &args)?;
+                session
+            }
+            r => {
+                r?;
+                OwnedObjectPath::try_from(AUTO_SESSION_PATH)?
+            }
+        };
+        self.session = Some(session);
+        Ok(())
+    }
+}
+
+fn set_brightness(
+    connection: &Connection,
+    session: &str,
+    args: &(&str, &str, u32),
+) -> zbus::Result<()> {
+    connection.call_method(
+        Some(LOGIN1_NAME),
+        session,
+        Some(SESSION_INTERFACE),
+        "SetBrightness",
+        args,
+    )?;
+    Ok(())
+}
+
+/// The caller is in no session, or logind does not know the auto path
+fn is_no_session(e: &OwnedErrorName) -> bool {
+    matches!(
+        e.as_str(),
+        "org.freedesktop.login1.NoSessionForPID" | "org.freedesktop.DBus.Error.UnknownObject"
+    )
+}
+
+/// The session the user's graphical display is in
+fn display_session(connection: &Connection) -> zbus::Result<OwnedObjectPath> {
+    let reply = connection.call_method(
+        Some(LOGIN1_NAME),
+        USER_PATH,
+        Some("org.freedesktop.DBus.Properties"),
+        "Get",
+        &(USER_INTERFACE, "Display"),
+    )?;
+    let value: OwnedValue = reply.body().deserialize()?;
+    let (_id, session) = <(String, OwnedObjectPath)>::try_from(value)?;
+    // "/" if the user has no display session
+    if session.as_str() == "/" {
+        return Err(zbus::Error::Failure(
+            "the user has no display session".into(),
+        ));
+    }
+    Ok(session)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_bus::TestBus;
+    use std::sync::{Arc, Mutex};
+    use zbus::blocking::connection;
+
+    /// Records (subsystem, name, value) for every call
+    struct MockSession {
+        calls: Arc<Mutex<Vec<(String, String, u32)>>>,
+    }
+
+    #[zbus::interface(name = "org.freedesktop.login1.Session")]
+    impl MockSession {
+        fn set_brightness(
+            &self,
+            subsystem: String,
+            name: String,
+            value: u32,
+        ) -> zbus::fdo::Result<()> {
+            if subsystem != "backlight" && subsystem != "leds" {
+                return Err(zbus::fdo::Error::InvalidArgs("bad subsystem".into()));
+            }
+            self.calls.lock().unwrap().push((subsystem, name, value));
+            Ok(())
+        }
+    }
+
+    struct MockUser;
+
+    #[zbus::interface(name = "org.freedesktop.login1.User")]
+    impl MockUser {
+        #[zbus(property)]
+        fn display(&self) -> (String, OwnedObjectPath) {
+            let path = OwnedObjectPath::try_from(DISPLAY_SESSION_PATH).unwrap();
+            ("2".into(), path)
+        }
+    }
+
+    const DISPLAY_SESSION_PATH: &str = "/org/freedesktop/login1/session/_32";
+
+    #[test]
+    fn test_set_brightness() {
+        let Some(bus) = TestBus::start("test_set_brightness") else {
+            return;
+        };
+        let address = bus.address();
+
+        let calls = Arc::new(Mutex::new(Vec::new()));
+        let mock = MockSession {
+            calls: calls.clone(),
+        };
+        let _server = connection::Builder::address(address)
+            .unwrap()
+            .name(LOGIN1_NAME)
+            .unwrap()
+            .serve_at(AUTO_SESSION_PATH, mock)
+            .unwrap()
+            .build()
+            .unwrap();
+
+        let mut session = Session::with_address(address);
+        session
+            .set_brightness("backlight", "intel_backlight", 4500)
+            .unwrap();
+        session
+            .set_brightness("leds", "tpacpi::kbd_backlight", 1)
+            .unwrap();
+        assert!(session.set_brightness("drm", "card0", 1).is_err());
+        assert_eq!(
+            *calls.lock().unwrap(),
+            vec![
+                ("backlight".to_string(), "intel_backlight".to_string(), 4500),
+                ("leds".to_string(), "tpacpi::kbd_backlight".to_string(), 1),
+            ]
+        );
+    }
+    #[test]
+    fn test_display_session_of_user() {
+        let Some(bus) = TestBus::start("test_display_session_of_user") else {
+            return;
+        };
+        let address = bus.address();
+
+        // a user service is in no session, so session/auto does not exist
+        let calls = Arc::new(Mutex::new(Vec::new()));
+        let mock = MockSession {
+            calls: calls.clone(),
+        };
+        let _server = connection::Builder::address(address)
+            .unwrap()
+            .name(LOGIN1_NAME)
+            .unwrap()
+            .serve_at(DISPLAY_SESSION_PATH, mock)
+            .unwrap()
+            .serve_at(USER_PATH, MockUser)
+            .unwrap()
+            .build()
+            .unwrap();
+
+        let mut session = Session::with_address(address);
+        session
+            .set_brightness("backlight", "amdgpu_bl0", 80)
+            .unwrap();
+        assert_eq!(
+            session.session.as_ref().map(|p| p.as_str()),
+            Some(DISPLAY_SESSION_PATH)
+        );
+        session
+            .set_brightness("backlight", "amdgpu_bl0", 90)
+            .unwrap();
+        assert_eq!(calls.lock().unwrap().len(), 2);
+    }
+}
@@ -1,46 +1,106 @@
 #![allow(dead_code, unused_imports)]
+mod bus;
 mod clamped;
 mod config;
+mod ddc;
+mod notify;
 mod options;
+mod osd;
 mod error;
+mod events;
+mod logind;
 mod scale;
+mod state;
+mod writer;
+#[cfg(test)]
+mod test_bus;
 
 use std::{
     env,
     ffi::{OsStr, OsString},
     fs::{self, read_to_string, write},
-    io::{self, Read, Write},
-    os::unix::net::{UnixListener, UnixStream},
+    io::{self, BufRead, BufReader, Read, Write},
+    os::unix::{
+        io::AsRawFd,
+        net::{UnixListener, UnixStream},
+    },
     path::{Path, PathBuf},
     process::Command,
     str::FromStr,
-    time::Duration,
+    sync::mpsc::{self, RecvTimeoutError},
+    thread,
+    time::{Duration, Instant},
 };
 use log::{trace, debug, info, warn, error};
+use signal_hook::{consts::SIGHUP, iterator::Signals};
 
-use backlightd::BacklightCommand;
+use backlightd::{
+    default_socket_path, BacklightCommand, BacklightEvent, BacklightReply, DisplayStatus, Level,
+    PowerState, WireFormat,
+};
 use clamped::*;
-use config::get_config;
+use config::{get_config, StartupLevel};
 use error::*;
+use events::Subscribers;
+use osd::Osd;
 use scale::*;
+use state::{LevelStore, Levels};
+use writer::{Fade, Writer};
 
 const RETRY_INTERVAL: Duration = Duration::from_secs(2);
+const IDLE_TIMEOUT: Duration = Duration::from_secs(2);
+const COMMAND_TIMEOUT: Duration = Duration::from_secs(5);
+/// How often a subscriber that is sent no events is checked for having gone
+const HANGUP_CHECK: Duration = Duration::from_secs(5);
+const MAX_COMMAND_LENGTH: usize = 4096;
 const STEPS_IN_REFERENCE_RANGE: f32 = 9.0;
-const DEFAULT_LEVEL: i8 = 4;
+const DEFAULT_LEVEL: f32 = 4.0;
 
 pub type Anything<T> = Result<T, Box<dyn std::error::Error>>;
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Clone)]
 pub enum ControlMethod {
     /// An existing path for the parameter, e.g. /sys/class/drm/card0-eDP-1/dpms
     SysFS(PathBuf),
     /// Display number reported by ddcutil detect
     DDCUtil(u8),
+    /// i2c-dev device of the monitor, e.g. /dev/i2c-5, for DDC/CI without
+    /// ddcutil
+    Ddc(PathBuf),
+    /// Device directory of a backlight or LED, e.g.
+    /// /sys/class/backlight/intel_backlight, with brightness and
+    /// max_brightness in it, and for backlights bl_power
+    Backlight(PathBuf),
+    /// Device directory whose brightness logind sets for the user's session,
+    /// with a SetBrightness call on the system bus, so that the daemon needs
+    /// no udev rule or root. Brightness is still read from the directory,
+    /// and there is no power control.
+    Logind(PathBuf),
     /// Name of the display used by swaymsg (e.g. eDP-1). Only used for on/off
     /// via DPMS.
     SwayDPMS(String),
 }
 
+/// Formatted as in the config file, e.g. "ddcutil:1"
+impl std::fmt::Display for ControlMethod {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ControlMethod::SysFS(p) => write!(f, "sysfs:{}", p.display()),
+            ControlMethod::DDCUtil(display) => write!(f, "ddcutil:{display}"),
+            ControlMethod::Ddc(p) => write!(f, "ddc:{}", p.display()),
+            ControlMethod::Backlight(p) => {
+                let (subsystem, name) = class_device(p);
+                write!(f, "{subsystem}:{name}")
+            }
+            ControlMethod::Logind(p) => {
+                let (subsystem, name) = class_device(p);
+                write!(f, "logind:{subsystem}:{name}")
+            }
+            ControlMethod::SwayDPMS(name) => write!(f, "swaydpms:{name}"),
+        }
+    }
+}
+
 impl ControlMethod {
     /// Display name like "card0-DP-1", filepath like "brightness"
     pub fn new_sysfs(display_name: impl AsRef<Path>, filepath: impl AsRef<Path>) -> Option<Self> {
@@ -53,6 +113,55 @@ impl ControlMethod {
             None
         }
     }
+    /// Subsystem is backlight or leds, name a directory under
+    /// <sysfs_root>/class/<subsystem>
+    pub fn new_backlight(sysfs_root: &Path, subsystem: &str, name: &str) -> Result<Self, Error> {
+        let p = sysfs_root.join("class").join(subsystem).join(name);
+        check_exists(&p)?;
+        Ok(Self::Backlight(p))
+    }
+    /// Subsystem is a directory under <sysfs_root>/class, name one in that
+    pub fn new_logind(sysfs_root: &Path, subsystem: &str, name: &str) -> Result<Self, Error> {
+        let p = sysfs_root.join("class").join(subsystem).join(name);
+        check_exists(&p)?;
+        Ok(Self::Logind(p))
+    }
+    /// The file that power is read from and written to, as for bl_power: 0
+    /// when on, 4 when off
+    fn power_path(&self) -> Option<PathBuf> {
+        match self {
+            ControlMethod::SysFS(p) => Some(p.clone()),
+            ControlMethod::Backlight(p) => Some(p.join("bl_power")),
+            _ => None,
+        }
+    }
+    /// Writes a raw brightness value, returning once the display has it.
+    /// Logind controls need a session, see brightness_writer.
+    pub fn write_brightness(&self, v: usize) -> Result<(), io::Error> {
+        match self {
+            ControlMethod::SysFS(p) => fs::write(p, v.to_string()),
+            ControlMethod::Backlight(p) => fs::write(p.join("brightness"), v.to_string()),
+            ControlMethod::DDCUtil(display) => ddcutil_set_brightness(*display, v),
+            ControlMethod::Ddc(p) => ddc::set_brightness(p, v),
+            ControlMethod::Logind(_) => Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "logind controls are written through a session",
+            )),
+            ControlMethod::SwayDPMS(_) => Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "cannot use swaydpms to set brightness",
+            )),
+        }
+    }
+}
+
+/// How an LED, such as a keyboard backlight, relates to the screens
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct Led {
+    /// Goes to the level of the screens after commands on all displays
+    follow: bool,
+    /// Off at this level and dimmer, when following
+    off_level: Option<f32>,
 }
 
 #[derive(Debug, PartialEq)]
@@ -61,12 +170,20 @@ pub struct Display {
     dpms_control: Option<ControlMethod>,
     brightness_control: Option<ControlMethod>,
     scale: BrightnessScale,
+    /// Applies brightness_control in the background
+    writer: Option<Writer>,
+    /// Brightness changes are faded if set
+    fade: Option<Fade>,
+    /// Feedback for up and down on this display alone
+    osd: Option<Osd>,
+    /// Set for LEDs, which are left out of commands on all displays
+    led: Option<Led>,
     name: OsString,
 }
 
 impl Display {
     pub fn is_on(&self) -> Result<bool, Error> {
-        if let Some(ControlMethod::SysFS(ref p)) = self.dpms_control {
+        if let Some(p) = self.dpms_control.as_ref().and_then(|c| c.power_path()) {
             let x = read_to_string(p)?;
             Ok(x == "0\n")
         } else {
@@ -74,7 +191,7 @@ impl Display {
         }
     }
     pub fn is_off(&self) -> Result<bool, Error> {
-        if let Some(ControlMethod::SysFS(ref p)) = self.dpms_control {
+        if let Some(p) = self.dpms_control.as_ref().and_then(|c| c.power_path()) {
             let x = read_to_string(p)?;
             Ok(x == "4\n")
         } else {
@@ -84,37 +201,140 @@ impl Display {
     pub fn get_brightness(&self) -> ClampedValue<usize> {
         self.scale.get_brightness()
     }
-    fn set_brightness(&mut self, v: usize) -> Result<(), io::Error> {
+    pub fn status(&self) -> DisplayStatus {
+        let v = self.get_brightness();
+        DisplayStatus {
+            name: self.name.clone(),
+            level: self.scale.level(),
+            percent: Some(self.scale.percent()),
+            value: *v,
+            clamp: v.state(),
+            power: match self.is_on() {
+                Ok(true) => PowerState::On,
+                Ok(false) => PowerState::Off,
+                Err(_) => PowerState::Unknown,
+            },
+            brightness_control: self.brightness_control.as_ref().map(|c| c.to_string()),
+            onoff_control: self.dpms_control.as_ref().map(|c| c.to_string()),
+        }
+    }
+    /// Queues the write, see check_write for how it went
+    fn set_brightness(&mut self, v: usize) {
         debug!("Setting brightness to {v}");
-        match self.brightness_control {
-            Some(ControlMethod::SysFS(ref p)) => fs::write(p, v.to_string()),
-            Some(ControlMethod::DDCUtil(display)) => ddcutil_set_brightness(display, v),
+        let Some(ref writer) = self.writer else {
             // cannot use sway to set brightness
-            _ => {
-                error!("Cannot use swaydpms to set brightness for {:?}", self.name);
-                Ok(())
-            },
+            error!("No brightness control to set brightness for {:?}", self.name);
+            return;
+        };
+        // a fade in progress is picked up from where it has got to
+        match (self.fade, writer.written()) {
+            (Some(fade), Some(from)) if from != v => {
+                let frames = writer.frames(fade.duration);
+                let values = self.scale.fade_from(from, frames, fade.curve);
+                writer.fade(values, writer.frame_interval())
+            }
+            _ => writer.set(v),
         }
     }
-    pub fn set_brightness_level(&mut self, level: i8) -> Result<ClampedValue<usize>, io::Error> {
+    /// Moves the scale with change and queues the new value
+    fn change_level<F>(&mut self, change: F) -> ClampedValue<usize>
+    where
+        F: FnOnce(&mut BrightnessScale) -> ClampedValue<usize>,
+    {
+        // a write that failed after its command was replied to has left the
+        // level where the display is not
+        let _ = self.check_write(Duration::ZERO);
+        let v = change(&mut self.scale);
+        self.set_brightness(*v);
+        v
+    }
+    /// Waits up to timeout for the latest change to be written, and fails if
+    /// the write did. Ok if it has not been made in time.
+    pub fn check_write(&mut self, timeout: Duration) -> Result<(), io::Error> {
+        let r = match self.writer {
+            Some(ref writer) => writer.check(timeout),
+            None => Ok(()),
+        };
+        self.settle(r)
+    }
+    /// Waits for the queued values to be written, for commands that must
+    /// know whether they took effect
+    pub fn flush(&mut self) -> Result<(), io::Error> {
+        let r = match self.writer {
+            Some(ref writer) => writer.wait(),
+            None => Ok(()),
+        };
+        self.settle(r)
+    }
+    /// After a failed write the level is put back to that of the last value
+    /// written, which the display still has
+    fn settle(&mut self, r: Result<(), io::Error>) -> Result<(), io::Error> {
+        if r.is_err() {
+            if let Some(v) = self.writer.as_ref().and_then(|w| w.written()) {
+                self.scale.sync_to_value(v);
+            }
+        }
+        r
+    }
+    pub fn set_brightness_level(&mut self, level: f32) -> ClampedValue<usize> {
         debug!("Setting brightness on {:?} to {level}", self.name);
-        let v = self.scale.set_level(level);
-        self.set_brightness(*v).map(|_| v)
+        self.change_level(|s| s.set_level(level))
+    }
+    /// Up by a number of levels, stopping at the limit level
+    pub fn brightness_up(&mut self, steps: f32, limit: f32) -> ClampedValue<usize> {
+        debug!("Brightness up {steps} on {:?}", self.name);
+        self.change_level(|s| s.up_by(steps, limit))
     }
-    pub fn brightness_up(&mut self) -> Result<ClampedValue<usize>, io::Error> {
-        debug!("Brightness up on {:?}", self.name);
-        let v = self.scale.up();
-        self.set_brightness(*v).map(|_| v)
+    /// Down by a number of levels, stopping at the limit level
+    pub fn brightness_down(&mut self, steps: f32, limit: f32) -> ClampedValue<usize> {
+        debug!("Brightness down {steps} on {:?}", self.name);
+        self.change_level(|s| s.down_by(steps, limit))
     }
-    pub fn brightness_down(&mut self) -> Result<ClampedValue<usize>, io::Error> {
-        debug!("Brightness down on {:?}", self.name);
-        let v = self.scale.down();
-        self.set_brightness(*v).map(|_| v)
+    /// The value the display reports, for controls that can be read back
+    fn read_brightness(&self) -> Result<Option<usize>, Error> {
+        match self.brightness_control {
+            Some(ControlMethod::SysFS(ref p)) => read_value_from_file(p).map(Some),
+            Some(ControlMethod::Backlight(ref p)) | Some(ControlMethod::Logind(ref p)) => {
+                read_value_from_file(p.join("brightness")).map(Some)
+            }
+            Some(ControlMethod::DDCUtil(display)) => {
+                Ok(Some(ddcutil_get_brightness(display)?.current.into()))
+            }
+            Some(ControlMethod::Ddc(ref p)) => Ok(Some(ddc::get_brightness(p)?.current.into())),
+            _ => Ok(None),
+        }
+    }
+    /// Follows changes made behind our back, e.g. with the monitor's buttons
+    pub fn sync(&mut self) -> Result<(), Error> {
+        // a value still on its way would be taken for an outside change. A
+        // failed write has been logged, and reading back sets the level right.
+        let _ = self.flush();
+        if let Some(v) = self.read_brightness()? {
+            debug!("Read back {v} from {:?}", self.name);
+            self.scale.sync_to_value(v);
+        }
+        Ok(())
+    }
+    fn show_osd(&mut self) {
+        if let Some(ref mut osd) = self.osd {
+            osd.show(self.scale.percent());
+        }
+    }
+    pub fn brightness_max(&mut self) -> ClampedValue<usize> {
+        debug!("Brightness max on {:?}", self.name);
+        let level = self.scale.max_level();
+        self.change_level(|s| s.set_level(level))
+    }
+    pub fn brightness_min(&mut self) -> ClampedValue<usize> {
+        debug!("Brightness min on {:?}", self.name);
+        let level = self.scale.min_level();
+        self.change_level(|s| s.set_level(level))
     }
     pub fn turn_on(&mut self) -> Result<(), io::Error> {
         debug!("Turning on {:?}", self.name);
         match self.dpms_control {
             Some(ControlMethod::SysFS(ref p)) => fs::write(p, "0"),
+            Some(ControlMethod::Backlight(ref p)) => fs::write(p.join("bl_power"), "0"),
             Some(ControlMethod::SwayDPMS(ref name)) => dpms_sway_turn_on(name),
             _ => {
                 error!("Cannot use ddcutil to turn on {:?}", self.name);
@@ -126,6 +346,7 @@ impl Display {
         debug!("Turning off {:?}", self.name);
         match self.dpms_control {
             Some(ControlMethod::SysFS(ref p)) => fs::write(p, "4"),
+            Some(ControlMethod::Backlight(ref p)) => fs::write(p.join("bl_power"), "4"),
             Some(ControlMethod::SwayDPMS(ref name)) => dpms_sway_turn_off(name),
             _ => {
                 error!("Cannot use ddcutil to turn off {:?}", self.name);
@@ -148,6 +369,90 @@ fn ddcutil_set_brightness(display: u8, v: usize) -> Result<(), io::Error> {
     Ok(())
 }
 
+/// Subsystem and device name, e.g. ("backlight", "intel_backlight"), from
+/// the device directory
+fn class_device(p: &Path) -> (String, String) {
+    let name = |p: Option<&Path>| {
+        let name = p.and_then(|p| p.file_name()).unwrap_or_default();
+        name.to_string_lossy().into_owned()
+    };
+    (name(p.parent()), name(Some(p)))
+}
+
+/// The function a display's writer calls. A logind control keeps its
+/// session, and the connection to logind, rather than look it up for every
+/// write.
+pub fn brightness_writer(
+    control: ControlMethod,
+) -> Box<dyn FnMut(usize) -> Result<(), io::Error> + Send> {
+    match control {
+        ControlMethod::Logind(p) => {
+            let mut session = logind::Session::new();
+            Box::new(move |v| logind_set_brightness(&mut session, &p, v))
+        }
+        control => Box::new(move |v| control.write_brightness(v)),
+    }
+}
+
+fn logind_set_brightness(session: &mut logind::Session, p: &Path, v: usize) -> Result<(), io::Error> {
+    let (subsystem, name) = class_device(p);
+    let v = u32::try_from(v)
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "value out of range for logind"))?;
+    session.set_brightness(&subsystem, &name, v)
+}
+
+/// Current and maximum of VCP 0x10, from "VCP 10 C 50 100"
+fn ddcutil_get_brightness(display: u8) -> Result<ddc::VcpValue, io::Error> {
+    let output = Command::new("/usr/bin/ddcutil")
+        .arg("getvcp")
+        .arg("10")
+        .arg("--brief")
+        .arg("--display")
+        .arg(display.to_string())
+        .output()?;
+    if !output.status.success() {
+        let msg = String::from_utf8_lossy(&output.stderr);
+        return Err(io::Error::other(format!("ddcutil getvcp failed: {}", msg.trim())));
+    }
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let fields: Vec<&str> = stdout.split_whitespace().collect();
+    match fields[..] {
+        ["VCP", "10", "C", current, max, ..] => match (current.parse(), max.parse()) {
+            (Ok(current), Ok(max)) => Ok(ddc::VcpValue { current, max }),
+            _ => Err(io::Error::other(format!("unexpected ddcutil output {stdout:?}"))),
+        },
+        _ => Err(io::Error::other(format!("unexpected ddcutil output {stdout:?}"))),
+    }
+}
+
+/// The maximum brightness a monitor reports, for displays whose config does
+/// not give one. None for controls that cannot tell, or if the monitor does
+/// not answer.
+pub fn read_max_brightness(control: &ControlMethod) -> Option<usize> {
+    let r = match control {
+        ControlMethod::DDCUtil(display) => ddcutil_get_brightness(*display)
+            .map(|v| v.max.into())
+            .map_err(Error::from),
+        ControlMethod::Ddc(ref p) => ddc::get_brightness(p)
+            .map(|v| v.max.into())
+            .map_err(Error::from),
+        ControlMethod::Backlight(ref p) | ControlMethod::Logind(ref p) => {
+            read_value_from_file(p.join("max_brightness"))
+        }
+        _ => return None,
+    };
+    match r {
+        Ok(max) => {
+            debug!("{control} reports a maximum brightness of {max}");
+            Some(max)
+        }
+        Err(e) => {
+            warn!("Could not read the maximum brightness from {control}: {e}");
+            None
+        }
+    }
+}
+
 fn dpms_sway_turn_on(name: impl AsRef<str>) -> Result<(), io::Error> {
     let mut child = Command::new("/usr/bin/swaymsg")
         .arg("-q") //quiet
@@ -194,40 +499,302 @@ fn establish_socket(path: impl AsRef<Path>) -> Anything<UnixListener> {
     if exists {
         warn!("Removing existing socket at {path:?}");
         // try to remove the socket, if it already exists
-        let _ = std::fs::remove_file(path)?;
+        std::fs::remove_file(path)?;
     }
     trace!("Binding socket");
     let listener = UnixListener::bind(path)?;
     Ok(listener)
 }
 
-fn run(listener: UnixListener, mut config: config::Config) -> Anything<()> {
-    let mut buf = Vec::new();
+// Accepting again cannot succeed after these
+fn is_fatal_accept_error(e: &io::Error) -> bool {
+    matches!(
+        e.raw_os_error(),
+        Some(libc::EBADF | libc::EINVAL | libc::ENOTSOCK | libc::EOPNOTSUPP | libc::EFAULT)
+    )
+}
+
+// Out of file descriptors or memory, worth waiting a moment before retrying
+fn is_resource_error(e: &io::Error) -> bool {
+    matches!(
+        e.raw_os_error(),
+        Some(libc::EMFILE | libc::ENFILE | libc::ENOBUFS | libc::ENOMEM)
+    )
+}
+
+/// A command read from a client, and where to send its reply
+enum Request {
+    Command {
+        cmd: BacklightCommand,
+        reply: mpsc::Sender<BacklightReply>,
+    },
+    /// Replies with the state of every display, then sends events
+    Subscribe {
+        reply: mpsc::Sender<BacklightReply>,
+        events: mpsc::Sender<BacklightEvent>,
+    },
+}
+
+fn run(
+    listener: UnixListener,
+    config: config::Config,
+    store: Option<LevelStore>,
+) -> Anything<()> {
+    let (requests, incoming) = mpsc::channel();
+    handle_sighup(requests.clone())?;
+    let saver = store.map(LevelStore::spawn_saver);
+    let executor = thread::spawn(move || execute_requests(incoming, config, saver));
+    // without the executor no command gets a reply, so the daemon is better
+    // off exiting and being restarted than hanging on
+    thread::spawn(move || {
+        match executor.join() {
+            Ok(()) => error!("Command executor stopped"),
+            Err(_) => error!("Command executor panicked"),
+        }
+        std::process::exit(1);
+    });
+    loop {
+        let client = match listener.accept() {
+            Ok((client, _)) => client,
+            Err(e) if is_fatal_accept_error(&e) => {
+                error!("Socket listener failed: {e}");
+                return Err(Box::new(e));
+            }
+            Err(e) => {
+                warn!("Could not accept connection: {e}");
+                if is_resource_error(&e) {
+                    thread::sleep(RETRY_INTERVAL);
+                }
+                continue;
+            }
+        };
+        // Clients are read concurrently, so a slow client cannot hold up the
+        // others, and a failing client cannot take the daemon down with it
+        let requests = requests.clone();
+        thread::spawn(move || {
+            if let Err(e) = handle_client(client, requests) {
+                warn!("Client connection failed: {e}");
+            }
+        });
+    }
+}
+
+/// Commands from all clients are executed one at a time, in the order they
+/// were received
+fn execute_requests(
+    incoming: mpsc::Receiver<Request>,
+    mut config: config::Config,
+    saver: Option<mpsc::Sender<Levels>>,
+) {
+    let mut subscribers = Subscribers::new();
+    for request in incoming {
+        let (cmd, reply) = match request {
+            Request::Command { cmd, reply } => (cmd, reply),
+            Request::Subscribe { reply, events } => {
+                let current: Vec<_> = config.displays.iter().map(|d| d.status()).collect();
+                if reply.send(BacklightReply::Ok(current.clone())).is_ok() {
+                    subscribers.add(events, current);
+                }
+                continue;
+            }
+        };
+        let is_reload = cmd == BacklightCommand::Reload;
+        let changes_brightness = cmd.changes_brightness();
+        let mut r = execute_command(cmd, &mut config);
+        if changes_brightness {
+            r = r.and_then(|reports| check_writes(reports, config.mut_displays()));
+        }
+        let r: BacklightReply = r.into();
+        if let (Some(ref mut notifier), BacklightReply::Ok(reports)) = (&mut config.notifier, &r) {
+            if changes_brightness {
+                notify_levels(notifier, reports);
+            }
+        }
+        if let Some(ref saver) = saver {
+            let _ = saver.send(display_levels(&config.displays));
+        }
+        if !subscribers.is_empty() {
+            let current = config.displays.iter().map(|d| d.status()).collect();
+            subscribers.publish(current, is_reload && r.is_ok());
+        }
+        trace!("Reply: {r}");
+        // the client may have gone away in the meantime
+        let _ = reply.send(r);
+    }
+}
+
+// The first display sets the progress bar, as levels are usually shared
+fn notify_levels(notifier: &mut notify::Notifier, reports: &[DisplayStatus]) {
+    let Some(percent) = reports.first().and_then(|d| d.percent) else {
+        return;
+    };
+    let body: Vec<String> = reports
+        .iter()
+        .filter_map(|d| {
+            let percent = d.percent?.clamp(0.0, 100.0).round();
+            Some(format!("{} {percent}%", d.name.to_string_lossy()))
+        })
+        .collect();
+    notifier.notify(&body.join(", "), percent);
+}
+
+/// Hands a command to the executor and waits for the reply
+fn submit(requests: &mpsc::Sender<Request>, cmd: BacklightCommand) -> BacklightReply {
+    let (reply, response) = mpsc::channel();
+    let sent = requests.send(Request::Command { cmd, reply });
+    match sent.ok().and_then(|_| response.recv().ok()) {
+        Some(r) => r,
+        None => BacklightReply::Error("daemon is shutting down".into()),
+    }
+}
+
+/// Turns the connection into an event stream, until the client goes away
+fn stream_events(
+    mut client: &UnixStream,
+    requests: &mpsc::Sender<Request>,
+    format: WireFormat,
+) -> io::Result<()> {
+    let (reply, response) = mpsc::channel();
+    let (events, incoming) = mpsc::channel();
+    let sent = requests.send(Request::Subscribe { reply, events });
+    let Some(r) = sent.ok().and_then(|_| response.recv().ok()) else {
+        let r = BacklightReply::Error("daemon is shutting down".into());
+        return writeln!(client, "{}", format.reply(&r));
+    };
+    writeln!(client, "{}", format.reply(&r))?;
+    loop {
+        match incoming.recv_timeout(HANGUP_CHECK) {
+            // fails once the client has closed the connection, or stops reading
+            Ok(event) => writeln!(client, "{}", format.event(&event))?,
+            // while nothing changes there is nothing to fail on, so look
+            Err(RecvTimeoutError::Timeout) if has_hung_up(client) => return Ok(()),
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => return Ok(()),
+        }
+    }
+}
+
+/// True once the client has closed the connection. A client that has only
+/// shut down writing is still listening.
+fn has_hung_up(client: &UnixStream) -> bool {
+    let mut fd = libc::pollfd {
+        fd: client.as_raw_fd(),
+        events: 0,
+        revents: 0,
+    };
+    let n = unsafe { libc::poll(&mut fd, 1, 0) };
+    n > 0 && fd.revents & (libc::POLLHUP | libc::POLLERR) != 0
+}
+
+fn is_timeout(e: &io::Error) -> bool {
+    matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut)
+}
+
+/// Reads up to and including a newline, stopping after MAX_COMMAND_LENGTH + 1
+/// bytes. Each read may wait for IDLE_TIMEOUT, and the whole line must arrive
+/// within COMMAND_TIMEOUT, so that a client sending a byte at a time cannot
+/// keep its connection for ever. Returns 0 at end of file.
+fn read_command(reader: &mut BufReader<&UnixStream>, line: &mut Vec<u8>) -> io::Result<usize> {
+    let deadline = Instant::now() + COMMAND_TIMEOUT;
     loop {
-        buf.clear();
-        let (mut client, _) = listener.accept()?;
-        client.read_to_end(&mut buf)?;
-        let cmd = BacklightCommand::try_from(buf.as_ref());
-        match cmd {
-            Ok(x) => {
-                execute_command(x, config.mut_displays())?;
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            return Err(io::Error::new(io::ErrorKind::TimedOut, "timed out reading command"));
+        }
+        reader.get_ref().set_read_timeout(Some(remaining.min(IDLE_TIMEOUT)))?;
+        let available = match reader.fill_buf() {
+            Ok(available) => available,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        if available.is_empty() {
+            return Ok(line.len());
+        }
+        let wanted = (MAX_COMMAND_LENGTH + 1 - line.len()).min(available.len());
+        let (n, done) = match available[..wanted].iter().position(|&c| c == b'\n') {
+            Some(i) => (i + 1, true),
+            None => (wanted, line.len() + wanted > MAX_COMMAND_LENGTH),
+        };
+        line.extend_from_slice(&available[..n]);
+        reader.consume(n);
+        if done {
+            return Ok(line.len());
+        }
+    }
+}
+
+/// Each line from the client is a command, or a batch of commands separated
+/// by ';', and gets one reply line in the same encoding, text or JSON. The
+/// last line needs no newline if the client shuts down its write half. A
+/// client that stays idle for IDLE_TIMEOUT, or takes longer than
+/// COMMAND_TIMEOUT to send a command, is disconnected. After "subscribe" the
+/// client is sent events instead, and anything else it sends is ignored.
+fn handle_client(client: UnixStream, requests: mpsc::Sender<Request>) -> Result<(), io::Error> {
+    client.set_write_timeout(Some(IDLE_TIMEOUT))?;
+    let mut reader = BufReader::new(&client);
+    let mut writer = &client;
+    let mut line = Vec::new();
+    let mut handled = 0;
+    loop {
+        line.clear();
+        match read_command(&mut reader, &mut line) {
+            Ok(0) => return Ok(()),
+            Ok(_) if line.len() > MAX_COMMAND_LENGTH => {
+                let reply = BacklightReply::Error("command is too long".into());
+                writeln!(writer, "{}", WireFormat::detect(&line).reply(&reply))?;
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "command is too long"));
             }
-            Err(e) => println!("Backlight command error {e:?}"),
+            Ok(_) => (),
+            // an idle client that has had its replies is simply done
+            Err(e) if is_timeout(&e) && handled > 0 && line.is_empty() => return Ok(()),
+            Err(e) => {
+                // the client may still be able to read a reply
+                let reply = if is_timeout(&e) {
+                    BacklightReply::Error("timed out waiting for a command".into())
+                } else {
+                    BacklightReply::Error(format!("could not read command: {e}"))
+                };
+                let _ = writeln!(writer, "{}", WireFormat::detect(&line).reply(&reply));
+                return Err(e);
+            }
+        }
+        let line = line.trim_ascii();
+        if line.is_empty() {
+            continue;
         }
+        let format = WireFormat::detect(line);
+        let reply = match format.parse_command(line) {
+            Ok(BacklightCommand::Subscribe) => return stream_events(&client, &requests, format),
+            Ok(cmd) => submit(&requests, cmd),
+            Err(e) => {
+                error!("Backlight command error {e:?}");
+                BacklightReply::Error(e.to_string())
+            }
+        };
+        writeln!(writer, "{}", format.reply(&reply))?;
+        handled += 1;
     }
 }
 
+/// SIGHUP reloads the config, queued behind any commands already received
+fn handle_sighup(requests: mpsc::Sender<Request>) -> Anything<()> {
+    let mut signals = Signals::new([SIGHUP])?;
+    thread::spawn(move || {
+        for _ in signals.forever() {
+            info!("SIGHUP received, reloading config");
+            // the reply has already been logged by the reload
+            submit(&requests, BacklightCommand::Reload);
+        }
+    });
+    Ok(())
+}
+
 fn main() -> Anything<()> {
     // parse command line options
     let cli_options = options::CliOptions::new();
 
-    // read config file
-    if let Some(config_path) = cli_options.config_file {
-        if ! matches!(config_path.try_exists(), Ok(true)) {
-            return Err(Box::new(Error::BadPath(config_path)));
-        }
-    }
-    let mut config = get_config()?;
+    // read config file: command line or environment, then the XDG location
+    let mut config = get_config(cli_options.config_file.as_deref(), None)?;
 
     // set up logging - assume systemd/journald is reading stderr
     let mut logging = env_logger::Builder::new();
@@ -238,142 +805,647 @@ fn main() -> Anything<()> {
     logging.init();
     info!("Logging enabled. Level is {:?}", config.log_level);
 
-    // set up the socket
-    let socket_path: PathBuf = if let Some(config_path) = cli_options.socket_path {
+    // set up the socket: command line or environment, then config file, then
+    // the XDG location
+    let socket_path: PathBuf = if let Some(cli_path) = cli_options.socket_path {
+        trace!("Socket path from command line or environment: {cli_path:?}");
+        cli_path
+    } else if let Some(config_path) = config.socket_path.clone() {
         trace!("Socket path from config file: {config_path:?}");
         config_path
     } else {
         trace!("Attempting to use XDG_RUNTIME_DIR to construct socket path");
-        let xdg_runtime_dir = env::var("XDG_RUNTIME_DIR")?;
-        PathBuf::from(xdg_runtime_dir).join("backlight")
+        default_socket_path().ok_or(Error::NoSocketPath)?
     };
     let listener = establish_socket(&socket_path)?;
     debug!("Made socket at {socket_path:?}");
 
-    // set default brightness
-    let default_level = config.default_level;
-    for d in config.mut_displays() {
-        let _ = d.set_brightness_level(default_level);
-    }
-    run(listener, config)
+    // set initial brightness, from the state file if so configured. Levels
+    // are saved either way, for a later switch to restore.
+    let state_file = config.state_file.clone().or_else(state::state_file_path);
+    let mut store = state_file.map(LevelStore::new);
+    let saved = match (config.startup_level, &mut store) {
+        (StartupLevel::Restore, Some(store)) => store.load().unwrap_or_else(|e| {
+            info!("No saved brightness levels at {:?}: {e}", store.path());
+            Levels::new()
+        }),
+        _ => Levels::new(),
+    };
+    let _ = restore_brightness(&saved, config.mut_displays());
+    run(listener, config, store)
 }
 
-fn execute_command(cmd: BacklightCommand, displays: &mut [Display]) -> Anything<()> {
+type CommandResult = Result<Vec<DisplayStatus>, Error>;
+
+fn execute_command(cmd: BacklightCommand, config: &mut config::Config) -> CommandResult {
     use backlightd::TargetDisplay;
+    let fine_step = config.fine_step;
+    let osd = &mut config.osd;
+    let displays = config.displays.as_mut_slice();
     match cmd {
         BacklightCommand::SwaySock(value) => {
             env::set_var("SWAYSOCK", value);
+            Ok(Vec::new())
         }
         BacklightCommand::On(display) => match display {
-            TargetDisplay::Display(name) => {
-                turn_on_display(&name, displays);
-            }
-            TargetDisplay::All => turn_on_all_displays(displays),
+            TargetDisplay::Display(name) => turn_on_display(&name, displays),
+            TargetDisplay::All => turn_on_all_displays(screens(displays)),
         },
         BacklightCommand::Off(display) => match display {
             TargetDisplay::Display(name) => turn_off_display(&name, displays),
-            TargetDisplay::All => turn_off_all_displays(displays),
+            TargetDisplay::All => turn_off_all_displays(screens(displays)),
         },
-        BacklightCommand::Up(display) => match display {
-            TargetDisplay::Display(name) => display_brightness_up(&name, displays),
-            TargetDisplay::All => all_brightness_up(displays),
+        BacklightCommand::Up(display, steps) => match display {
+            TargetDisplay::Display(name) => display_brightness_up(&name, steps, displays),
+            TargetDisplay::All => show_all_osd(
+                with_leds_following(displays, |s| all_brightness_up(steps, s)),
+                osd,
+            ),
         },
-        BacklightCommand::Down(display) => match display {
-            TargetDisplay::Display(name) => display_brightness_down(&name, displays),
-            TargetDisplay::All => all_brightness_down(displays),
+        BacklightCommand::Down(display, steps) => match display {
+            TargetDisplay::Display(name) => display_brightness_down(&name, steps, displays),
+            TargetDisplay::All => show_all_osd(
+                with_leds_following(displays, |s| all_brightness_down(steps, s)),
+                osd,
+            ),
+        },
+        BacklightCommand::FineUp(display) => match display {
+            TargetDisplay::Display(name) => display_brightness_up(&name, fine_step, displays),
+            TargetDisplay::All => show_all_osd(
+                with_leds_following(displays, |s| all_brightness_up(fine_step, s)),
+                osd,
+            ),
+        },
+        BacklightCommand::FineDown(display) => match display {
+            TargetDisplay::Display(name) => display_brightness_down(&name, fine_step, displays),
+            TargetDisplay::All => show_all_osd(
+                with_leds_following(displays, |s| all_brightness_down(fine_step, s)),
+                osd,
+            ),
+        },
+        BacklightCommand::Set(display, level) => match display {
+            TargetDisplay::Display(name) => display_brightness_set(&name, level, displays),
+            TargetDisplay::All => with_leds_following(displays, |s| all_brightness_set(level, s)),
         },
         BacklightCommand::Toggle(display) => match display {
             TargetDisplay::Display(name) => toggle_display(&name, displays),
-            TargetDisplay::All => toggle_all_displays(displays),
+            TargetDisplay::All => toggle_all_displays(screens(displays)),
+        },
+        BacklightCommand::Max(display) => match display {
+            TargetDisplay::Display(name) => display_brightness_max(&name, displays),
+            TargetDisplay::All => with_leds_following(displays, all_brightness_max),
         },
-        BacklightCommand::Max(_) => todo!(),
-        BacklightCommand::Min(_) => todo!(),
-        BacklightCommand::Default(_) => todo!(),
+        BacklightCommand::Min(display) => match display {
+            TargetDisplay::Display(name) => display_brightness_min(&name, displays),
+            TargetDisplay::All => with_leds_following(displays, all_brightness_min),
+        },
+        BacklightCommand::Default(display) => match display {
+            TargetDisplay::Display(name) => display_brightness_default(&name, displays),
+            TargetDisplay::All => with_leds_following(displays, all_brightness_default),
+        },
+        BacklightCommand::Status(display) => match display {
+            TargetDisplay::Display(name) => report_display(&name, displays),
+            TargetDisplay::All => report_all_displays(displays),
+        },
+        BacklightCommand::List => report_all_displays(displays),
+        BacklightCommand::Reload => reload_config(config),
+        BacklightCommand::Sync(display) => match display {
+            TargetDisplay::Display(name) => display_sync(&name, displays),
+            TargetDisplay::All => all_sync(displays),
+        },
+        BacklightCommand::Batch(cmds) => execute_batch(cmds, config),
+        // Subscriptions are set up by handle_client; without an event
+        // stream this is just a status query
+        BacklightCommand::Subscribe => report_all_displays(displays),
     }
-    Ok(())
 }
 
-fn turn_on_display(name: &OsStr, displays: &mut [Display]) {
-    for d in displays {
-        if d.name == name {
-            let _ = d.turn_on();
+/// Executes every command, or none: levels are put back as they were if any
+/// command fails, or any write does. Reports the final state of each
+/// affected display once.
+fn execute_batch(cmds: Vec<BacklightCommand>, config: &mut config::Config) -> CommandResult {
+    if let Some(cmd) = cmds.iter().find(|c| !c.can_batch()) {
+        return Err(Error::CannotBatch(cmd.to_string()));
+    }
+    let before: Vec<f32> = config.displays.iter().map(|d| d.scale.level()).collect();
+    let mut affected: Vec<OsString> = Vec::new();
+    for cmd in cmds {
+        match execute_command(cmd, config) {
+            Ok(reports) => affected.extend(reports.into_iter().map(|r| r.name)),
+            Err(e) => {
+                roll_back_levels(&before, config.mut_displays());
+                return Err(e);
+            }
         }
     }
-}
-fn turn_on_all_displays(displays: &mut [Display]) {
-    for d in displays {
-        let _ = d.turn_on();
+    if let Err(e) = flush_all(config.mut_displays()) {
+        roll_back_levels(&before, config.mut_displays());
+        return Err(e);
     }
+    let reports = config
+        .displays
+        .iter()
+        .filter(|d| affected.contains(&d.name))
+        .map(|d| d.status())
+        .collect();
+    Ok(reports)
 }
-fn turn_off_display(name: &OsStr, displays: &mut [Display]) {
-    for d in displays {
-        if d.name == name {
-            let _ = d.turn_off();
+
+fn roll_back_levels(levels: &[f32], displays: &mut [Display]) {
+    for (d, level) in displays.iter_mut().zip(levels) {
+        if d.scale.level() != *level {
+            debug!("Rolling back {:?} to {level}", d.name);
+            d.set_brightness_level(*level);
+            if let Err(e) = d.flush() {
+                error!("Could not roll back {:?}: {e}", d.name);
+            }
         }
     }
 }
-fn turn_off_all_displays(displays: &mut [Display]) {
-    for d in displays {
-        let _ = d.turn_off();
+
+/// How long a command waits for its writes to start, so that a failure is
+/// replied to the command that made it. Later failures are only logged.
+const WRITE_CHECK_TIMEOUT: Duration = Duration::from_millis(100);
+
+/// Fails if a write for one of the reported displays did, within
+/// WRITE_CHECK_TIMEOUT
+fn check_writes(reports: Vec<DisplayStatus>, displays: &mut [Display]) -> CommandResult {
+    let deadline = Instant::now() + WRITE_CHECK_TIMEOUT;
+    let mut result = Ok(());
+    for d in displays.iter_mut() {
+        if !reports.iter().any(|r| r.name == d.name) {
+            continue;
+        }
+        let timeout = deadline.saturating_duration_since(Instant::now());
+        if let Err(e) = d.check_write(timeout) {
+            result = result.and(Err(Error::from(e)));
+        }
     }
+    result.map(|_| reports)
 }
-fn toggle_display(name: &OsStr, displays: &mut [Display]) {
+
+/// Waits for every display's writes, returning the first failure
+fn flush_all(displays: &mut [Display]) -> Result<(), Error> {
+    let mut result = Ok(());
     for d in displays {
-        if d.name == name {
-            match d.is_on() {
-                Ok(true) => {
-                    let _ = d.turn_off();
-                }
-                Ok(false) => {
-                    let _ = d.turn_on();
-                }
-                Err(e) => println!("Error getting state of {name:?}: {e:?}"),
-            }
+        if let Err(e) = d.flush() {
+            error!("Could not set brightness on {:?}: {e}", d.name);
+            result = result.and(Err(Error::from(e)));
         }
     }
+    result
 }
-fn toggle_all_displays(displays: &mut [Display]) {
-    if let Some(lead_display) = displays.first() {
-        match lead_display.is_on() {
-            Ok(true) => turn_off_all_displays(displays),
-            Ok(false) => turn_on_all_displays(displays),
-            Err(e) => println!("Error getting state of lead display: {e:?}"),
+
+/// The new config is read in full before it replaces the old one, so a bad
+/// config leaves the daemon running as it was. Levels carry over by name.
+fn reload_config(config: &mut config::Config) -> CommandResult {
+    let mut new_config = match get_config(config.path.as_deref(), Some(config)) {
+        Ok(c) => c,
+        Err(e) => {
+            error!("Rejected new config, keeping the old one: {e}");
+            return Err(e);
         }
+    };
+    if new_config.socket_path != config.socket_path {
+        warn!("socket_path changes take effect on restart");
+    }
+    if new_config.state_file != config.state_file {
+        warn!("state_file changes take effect on restart");
+    }
+    if new_config.log_level != config.log_level || new_config.log_timestamp != config.log_timestamp {
+        warn!("Logging changes take effect on restart");
+    }
+    let levels = display_levels(&config.displays);
+    // the old writers must be done before the new ones write to the same
+    // displays, or a late value from them would win. Failures were logged.
+    let _ = flush_all(config.mut_displays());
+    // once the new config is in place the reload has happened, and is
+    // replied and published as such even if a display could not be written
+    if let Err(e) = restore_brightness(&levels, new_config.mut_displays()) {
+        warn!("Not every display took its level after the reload: {e}");
     }
+    // keep replacing the same notification
+    if new_config.notifier.is_some() && config.notifier.is_some() {
+        new_config.notifier = config.notifier.take();
+    }
+    *config = new_config;
+    info!("Reloaded config from {:?}", config.path);
+    report_all_displays(&config.displays)
 }
 
-fn display_brightness_up(name: &OsStr, displays: &mut [Display]) {
+/// Applies f to every display called name and reports on each of them. All
+/// matching displays are attempted, the first failure is returned.
+fn with_display<F, T, E>(name: &OsStr, displays: &mut [Display], mut f: F) -> CommandResult
+where
+    F: FnMut(&mut Display) -> Result<T, E>,
+    Error: From<E>,
+{
+    let mut reports = Vec::new();
+    let mut result = Ok(());
     // Consider every display, as several displays may share the same name
-    for d in displays {
-        if d.name == name {
-            if !d.get_brightness().is_max() {
-                let _ = d.brightness_up();
-            }
+    for d in displays.iter_mut().filter(|d| d.name == name) {
+        if let Err(e) = f(d) {
+            let e = Error::from(e);
+            error!("Command failed on {name:?}: {e}");
+            result = result.and(Err(e));
         }
+        reports.push(d.status());
+    }
+    if reports.is_empty() {
+        return Err(Error::NoSuchDisplay(name.into()));
     }
+    result.map(|_| reports)
 }
-fn display_brightness_down(name: &OsStr, displays: &mut [Display]) {
-    // Consider every display, as several displays may share the same name
-    for d in displays {
-        if d.name == name {
-            if !d.get_brightness().is_min() {
-                let _ = d.brightness_down();
-            }
+
+/// Applies f to every display and reports on all of them
+fn with_all_displays<F, T, E>(displays: &mut [Display], mut f: F) -> CommandResult
+where
+    F: FnMut(&mut Display) -> Result<T, E>,
+    Error: From<E>,
+{
+    let mut reports = Vec::new();
+    let mut result = Ok(());
+    for d in displays.iter_mut() {
+        if let Err(e) = f(d) {
+            let e = Error::from(e);
+            error!("Command failed on {:?}: {e}", d.name);
+            result = result.and(Err(e));
         }
+        reports.push(d.status());
     }
+    result.map(|_| reports)
 }
 
-fn all_brightness_up(displays: &mut [Display]) {
-    if displays.iter().any(|d| !d.get_brightness().is_max()) {
-        for d in displays {
-            let _ = d.brightness_up();
+/// LEDs come after the screens, see config::Config::displays
+fn split_leds(displays: &mut [Display]) -> (&mut [Display], &mut [Display]) {
+    let n = displays.iter().position(|d| d.led.is_some()).unwrap_or(displays.len());
+    displays.split_at_mut(n)
+}
+fn screens(displays: &mut [Display]) -> &mut [Display] {
+    split_leds(displays).0
+}
+
+/// Applies f to the screens, then takes the LEDs that follow them to the
+/// level of the first screen. Reports on the screens, then those LEDs.
+fn with_leds_following<F>(displays: &mut [Display], f: F) -> CommandResult
+where
+    F: FnOnce(&mut [Display]) -> CommandResult,
+{
+    let (screens, leds) = split_leds(displays);
+    let mut reports = f(screens)?;
+    if let Some(level) = screens.first().map(|d| d.scale.level()) {
+        reports.extend(follow_level(level, leds));
+    }
+    Ok(reports)
+}
+
+fn follow_level(level: f32, leds: &mut [Display]) -> Vec<DisplayStatus> {
+    let mut reports = Vec::new();
+    for d in leds.iter_mut() {
+        let Some(led) = d.led.filter(|l| l.follow) else {
+            continue;
+        };
+        // off keeps the level of the screens, unless that would still light it
+        let level = match led.off_level {
+            Some(off) if level >= off => level.max(d.scale.min_level()),
+            _ => level,
+        };
+        d.set_brightness_level(level);
+        reports.push(d.status());
+    }
+    reports
+}
+
+fn report_display(name: &OsStr, displays: &mut [Display]) -> CommandResult {
+    with_display(name, displays, |_| Ok::<_, Error>(()))
+}
+fn report_all_displays(displays: &[Display]) -> CommandResult {
+    Ok(displays.iter().map(|d| d.status()).collect())
+}
+
+fn turn_on_display(name: &OsStr, displays: &mut [Display]) -> CommandResult {
+    with_display(name, displays, |d| d.turn_on())
+}
+fn turn_on_all_displays(displays: &mut [Display]) -> CommandResult {
+    with_all_displays(displays, |d| d.turn_on())
+}
+fn turn_off_display(name: &OsStr, displays: &mut [Display]) -> CommandResult {
+    with_display(name, displays, |d| d.turn_off())
+}
+fn turn_off_all_displays(displays: &mut [Display]) -> CommandResult {
+    with_all_displays(displays, |d| d.turn_off())
+}
+fn display_sync(name: &OsStr, displays: &mut [Display]) -> CommandResult {
+    with_display(name, displays, |d| d.sync())
+}
+fn all_sync(displays: &mut [Display]) -> CommandResult {
+    with_all_displays(displays, |d| d.sync())
+}
+fn toggle(d: &mut Display) -> Result<(), Error> {
+    if d.is_on()? {
+        d.turn_off()?;
+    } else {
+        d.turn_on()?;
+    }
+    Ok(())
+}
+fn toggle_display(name: &OsStr, displays: &mut [Display]) -> CommandResult {
+    with_display(name, displays, toggle)
+}
+fn toggle_all_displays(displays: &mut [Display]) -> CommandResult {
+    let Some(lead_display) = displays.first() else {
+        return Ok(Vec::new());
+    };
+    match lead_display.is_on() {
+        Ok(true) => turn_off_all_displays(displays),
+        Ok(false) => turn_on_all_displays(displays),
+        Err(e) => {
+            error!("Error getting state of lead display: {e:?}");
+            Err(e)
         }
     }
 }
-fn all_brightness_down(displays: &mut [Display]) {
-    if displays.iter().any(|d| !d.get_brightness().is_min()) {
-        for d in displays {
-            let _ = d.brightness_down();
+
+fn display_brightness_up(name: &OsStr, steps: f32, displays: &mut [Display]) -> CommandResult {
+    with_display(name, displays, |d| {
+        let limit = d.scale.max_level();
+        let v = d.brightness_up(steps, limit);
+        d.show_osd();
+        Ok::<_, io::Error>(v)
+    })
+}
+fn display_brightness_down(name: &OsStr, steps: f32, displays: &mut [Display]) -> CommandResult {
+    with_display(name, displays, |d| {
+        let limit = d.scale.min_level();
+        let v = d.brightness_down(steps, limit);
+        d.show_osd();
+        Ok::<_, io::Error>(v)
+    })
+}
+
+// Displays stay on a common level, so the range for "all" runs from the level
+// at which every display is clamped to max, to the one where every display is
+// clamped to min. Repeated up/down steps stop at these levels.
+fn all_level_limits(displays: &[Display]) -> Option<(f32, f32)> {
+    let top = displays.iter().map(|d| d.scale.max_level()).reduce(f32::min)?;
+    let bottom = displays.iter().map(|d| d.scale.min_level()).reduce(f32::max)?;
+    Some((top, bottom))
+}
+fn clamp_level(level: f32, (top, bottom): (f32, f32)) -> f32 {
+    if top <= bottom {
+        level.clamp(top, bottom)
+    } else {
+        level
+    }
+}
+
+fn all_brightness_up(steps: f32, displays: &mut [Display]) -> CommandResult {
+    match all_level_limits(displays) {
+        Some((limit, _)) if displays.iter().any(|d| !d.get_brightness().is_max()) => {
+            with_all_displays(displays, |d| Ok::<_, Error>(d.brightness_up(steps, limit)))
         }
+        _ => report_all_displays(displays),
+    }
+}
+fn all_brightness_down(steps: f32, displays: &mut [Display]) -> CommandResult {
+    match all_level_limits(displays) {
+        Some((_, limit)) if displays.iter().any(|d| !d.get_brightness().is_min()) => {
+            with_all_displays(displays, |d| Ok::<_, Error>(d.brightness_down(steps, limit)))
+        }
+        _ => report_all_displays(displays),
+    }
+}
+
+// Displays share a level, so the first one speaks for all of them
+fn show_all_osd(result: CommandResult, osd: &mut Option<Osd>) -> CommandResult {
+    if let (Ok(reports), Some(osd)) = (&result, osd) {
+        if let Some(percent) = reports.first().and_then(|d| d.percent) {
+            osd.show(percent);
+        }
+    }
+    result
+}
+
+fn resolve_level(d: &Display, level: Level) -> f32 {
+    match level {
+        Level::Absolute(l) => l,
+        Level::Percent(p) => d.scale.level_for_percent(p),
+    }
+}
+fn display_brightness_set(name: &OsStr, level: Level, displays: &mut [Display]) -> CommandResult {
+    with_display(name, displays, |d| {
+        let level = d.scale.clamp_level(resolve_level(d, level));
+        Ok::<_, Error>(d.set_brightness_level(level))
+    })
+}
+fn all_brightness_set(level: Level, displays: &mut [Display]) -> CommandResult {
+    let Some(limits) = all_level_limits(displays) else {
+        return Ok(Vec::new());
+    };
+    with_all_displays(displays, |d| {
+        let level = clamp_level(resolve_level(d, level), limits);
+        Ok::<_, Error>(d.set_brightness_level(level))
+    })
+}
+fn display_brightness_default(name: &OsStr, displays: &mut [Display]) -> CommandResult {
+    with_display(name, displays, |d| {
+        let level = d.scale.clamp_level(d.scale.default_level());
+        Ok::<_, Error>(d.set_brightness_level(level))
+    })
+}
+fn all_brightness_default(displays: &mut [Display]) -> CommandResult {
+    let Some(limits) = all_level_limits(displays) else {
+        return Ok(Vec::new());
+    };
+    with_all_displays(displays, |d| {
+        let level = clamp_level(d.scale.default_level(), limits);
+        Ok::<_, Error>(d.set_brightness_level(level))
+    })
+}
+
+fn display_levels(displays: &[Display]) -> Levels {
+    displays.iter().map(|d| (d.name.clone(), d.scale.level())).collect()
+}
+/// Saved levels where there are any, default levels for the other displays.
+/// Screens are kept to their common range, LEDs to their own, and LEDs that
+/// follow the screens are taken to their level. Returns once the levels have
+/// been written.
+fn restore_brightness(saved: &Levels, displays: &mut [Display]) -> CommandResult {
+    let limits = all_level_limits(screens(displays));
+    for d in displays.iter_mut() {
+        let level = match saved.get(&d.name) {
+            Some(level) => *level,
+            None => d.scale.default_level(),
+        };
+        let level = match (d.led, limits) {
+            (None, Some(limits)) => clamp_level(level, limits),
+            _ => d.scale.clamp_level(level),
+        };
+        d.set_brightness_level(level);
+    }
+    let (screens, leds) = split_leds(displays);
+    if let Some(level) = screens.first().map(|d| d.scale.level()) {
+        follow_level(level, leds);
+    }
+    flush_all(displays)?;
+    report_all_displays(displays)
+}
+
+fn display_brightness_max(name: &OsStr, displays: &mut [Display]) -> CommandResult {
+    with_display(name, displays, |d| Ok::<_, Error>(d.brightness_max()))
+}
+fn display_brightness_min(name: &OsStr, displays: &mut [Display]) -> CommandResult {
+    with_display(name, displays, |d| Ok::<_, Error>(d.brightness_min()))
+}
+
+fn all_brightness_max(displays: &mut [Display]) -> CommandResult {
+    match all_level_limits(displays) {
+        Some((level, _)) => all_brightness_level(level, displays),
+        None => Ok(Vec::new()),
+    }
+}
+fn all_brightness_min(displays: &mut [Display]) -> CommandResult {
+    match all_level_limits(displays) {
+        Some((_, level)) => all_brightness_level(level, displays),
+        None => Ok(Vec::new()),
+    }
+}
+fn all_brightness_level(level: f32, displays: &mut [Display]) -> CommandResult {
+    with_all_displays(displays, |d| Ok::<_, Error>(d.set_brightness_level(level)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn display(name: &str, led: Option<Led>) -> Display {
+        // a keyboard backlight has a few values, a screen many
+        let mut scale = ScaleBuilder::new();
+        scale.max_value(if led.is_some() { 2 } else { 100 });
+        Display {
+            dpms_control: None,
+            brightness_control: None,
+            scale: scale.make().unwrap(),
+            writer: None,
+            fade: None,
+            osd: None,
+            led,
+            name: name.into(),
+        }
+    }
+    fn led(follow: bool, off_level: Option<f32>) -> Option<Led> {
+        Some(Led { follow, off_level })
+    }
+    fn names(reports: &[DisplayStatus]) -> Vec<&OsStr> {
+        reports.iter().map(|r| r.name.as_os_str()).collect()
+    }
+
+    #[test]
+    fn test_leds_follow_the_screens() {
+        let mut displays = vec![
+            display("laptop", None),
+            display("desktop", None),
+            display("kbd", led(true, None)),
+            display("status", led(false, None)),
+        ];
+        let reports = with_leds_following(&mut displays, |s| {
+            all_brightness_set(Level::Absolute(2.0), s)
+        })
+        .unwrap();
+        assert_eq!(names(&reports), ["laptop", "desktop", "kbd"]);
+        assert_eq!(displays[2].scale.level(), 2.0);
+        assert_eq!(displays[3].scale.level(), DEFAULT_LEVEL);
+    }
+    #[test]
+    fn test_led_off_level() {
+        let mut displays = vec![display("laptop", None), display("kbd", led(true, Some(3.0)))];
+        let mut set_all = |level| {
+            with_leds_following(&mut displays, |s| all_brightness_set(Level::Absolute(level), s))
+                .unwrap();
+            *displays[1].get_brightness()
+        };
+        assert_eq!(set_all(2.0), 1);
+        // dark at the off level, although the LED's own scale would still
+        // light it there
+        assert_eq!(set_all(3.0), 0);
+        assert_eq!(set_all(7.0), 0);
+        assert_eq!(set_all(2.0), 1);
+        assert_eq!(displays[1].scale.level(), 2.0);
+    }
+    #[test]
+    fn test_leds_are_not_switched_with_the_screens() {
+        let dir = std::env::temp_dir().join(format!("backlightd-main-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let mut displays = vec![display("laptop", None), display("kbd", led(true, None))];
+        for d in displays.iter_mut() {
+            let p = dir.join(d.name.clone());
+            fs::write(&p, "0\n").unwrap();
+            d.dpms_control = Some(ControlMethod::SysFS(p));
+        }
+        assert_eq!(screens(&mut displays).len(), 1);
+        let reports = toggle_all_displays(screens(&mut displays)).unwrap();
+        assert_eq!(names(&reports), ["laptop"]);
+        assert_eq!(fs::read_to_string(dir.join("laptop")).unwrap(), "4");
+        assert_eq!(fs::read_to_string(dir.join("kbd")).unwrap(), "0\n");
+        turn_on_all_displays(screens(&mut displays)).unwrap();
+        assert_eq!(fs::read_to_string(dir.join("laptop")).unwrap(), "0");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+    #[test]
+    fn test_restore_takes_leds_to_the_screens() {
+        let mut displays = vec![
+            display("laptop", None),
+            display("kbd", led(true, None)),
+            display("status", led(false, None)),
+        ];
+        let saved = Levels::from([
+            ("laptop".into(), 1.0),
+            ("kbd".into(), 5.0),
+            ("status".into(), 5.0),
+        ]);
+        let reports = restore_brightness(&saved, &mut displays).unwrap();
+        assert_eq!(names(&reports), ["laptop", "kbd", "status"]);
+        assert_eq!(displays[1].scale.level(), 1.0);
+        assert_eq!(displays[2].scale.level(), 5.0);
+    }
+    #[test]
+    fn test_subscriber_hang_up() {
+        let (client, server) = UnixStream::pair().unwrap();
+        assert!(!has_hung_up(&server));
+        client.shutdown(std::net::Shutdown::Write).unwrap();
+        assert!(!has_hung_up(&server));
+        drop(client);
+        assert!(has_hung_up(&server));
+    }
+    #[test]
+    fn test_failed_write_is_replied_and_undone() {
+        use std::sync::{
+            atomic::{AtomicBool, Ordering},
+            Arc,
+        };
+        let fail = Arc::new(AtomicBool::new(false));
+        let failing = fail.clone();
+        let mut laptop = display("laptop", None);
+        laptop.writer = Some(Writer::new("laptop".into(), Duration::ZERO, move |_| {
+            if failing.load(Ordering::SeqCst) {
+                Err(io::Error::other("gone"))
+            } else {
+                Ok(())
+            }
+        }));
+        let mut displays = vec![laptop];
+        let name = OsStr::new("laptop");
+        let set = |level, displays: &mut [Display]| {
+            let reports = display_brightness_set(name, Level::Absolute(level), displays)?;
+            check_writes(reports, displays)
+        };
+        set(2.0, &mut displays).unwrap();
+        fail.store(true, Ordering::SeqCst);
+        assert!(set(5.0, &mut displays).is_err());
+        assert_eq!(displays[0].scale.level(), 2.0);
+        // the next command is not failed for it
+        fail.store(false, Ordering::SeqCst);
+        set(3.0, &mut displays).unwrap();
+        assert_eq!(displays[0].scale.level(), 3.0);
     }
 }
@@ -0,0 +1,135 @@
+use crate::bus::{Bus, BusConnection};
+use log::warn;
+use std::{collections::HashMap, time::Duration};
+use zbus::zvariant::Value;
+
+const NOTIFICATIONS_NAME: &str = "org.freedesktop.Notifications";
+const NOTIFICATIONS_PATH: &str = "/org/freedesktop/Notifications";
+// a notification server that does not answer must not hold up commands
+const NOTIFY_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Desktop notifications over the session bus. Every notification replaces
+/// the previous one, so that a burst of keypresses updates a single bubble.
+pub struct Notifier {
+    connection: BusConnection,
+    id: u32,
+}
+
+impl Notifier {
+    pub fn new() -> Self {
+        Self::on_bus(Bus::Session)
+    }
+    pub fn with_address(address: impl Into<String>) -> Self {
+        Self::on_bus(Bus::Address(address.into()))
+    }
+    fn on_bus(bus: Bus) -> Self {
+        Self {
+            connection: BusConnection::new(bus, "the notification server", NOTIFY_TIMEOUT),
+            id: 0,
+        }
+    }
+    /// Shows body with a progress bar at percent. Failures are only logged.
+    pub fn notify(&mut self, body: &str, percent: f32) {
+        let percent = percent.clamp(0.0, 100.0).round() as i32;
+        match self.send(body, percent) {
+            Ok(id) => self.id = id,
+            Err(e) => {
+                warn!("Could not send notification: {e}");
+                self.connection.reset();
+            }
+        }
+    }
+    fn send(&mut self, body: &str, percent: i32) -> zbus::Result<u32> {
+        let connection = self.connection.get()?;
+        let hints = HashMap::from([("value", Value::from(percent))]);
+        let actions: Vec<&str> = Vec::new();
+        let args = (
+            "backlightd",
+            self.id,
+            "display-brightness",
+            "Brightness",
+            body,
+            actions,
+            hints,
+            -1i32,
+        );
+        let reply = connection.call_method(
+            Some(NOTIFICATIONS_NAME),
+            NOTIFICATIONS_PATH,
+            Some(NOTIFICATIONS_NAME),
+            "Notify",
+            &args,
+        )?;
+        reply.body().deserialize()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_bus::TestBus;
+    use std::sync::{Arc, Mutex};
+    use zbus::{blocking::connection, zvariant::OwnedValue};
+
+    /// Records (replaces_id, body, progress) for every call
+    struct StubServer {
+        calls: Arc<Mutex<Vec<(u32, String, i32)>>>,
+    }
+
+    #[zbus::interface(name = "org.freedesktop.Notifications")]
+    impl StubServer {
+        #[allow(clippy::too_many_arguments)]
+        fn notify(
+            &self,
+            _app_name: String,
+            replaces_id: u32,
+            _app_icon: String,
+            _summary: String,
+            body: String,
+            _actions: Vec<String>,
+            hints: HashMap<String, OwnedValue>,
+            _expire_timeout: i32,
+        ) -> u32 {
+            let value = i32::try_from(&hints["value"]).unwrap();
+            let mut calls = self.calls.lock().unwrap();
+            calls.push((replaces_id, body, value));
+            if replaces_id == 0 {
+                calls.len() as u32 + 40
+            } else {
+                replaces_id
+            }
+        }
+    }
+
+    #[test]
+    fn test_notifications_replace_each_other() {
+        let Some(bus) = TestBus::start("test_notifications_replace_each_other") else {
+            return;
+        };
+        let address = bus.address();
+
+        let calls = Arc::new(Mutex::new(Vec::new()));
+        let stub = StubServer {
+            calls: calls.clone(),
+        };
+        let _server = connection::Builder::address(address)
+            .unwrap()
+            .name(NOTIFICATIONS_NAME)
+            .unwrap()
+            .serve_at(NOTIFICATIONS_PATH, stub)
+            .unwrap()
+            .build()
+            .unwrap();
+
+        let mut notifier = Notifier::with_address(address);
+        notifier.notify("laptop 56%", 55.6);
+        notifier.notify("laptop 100%", 111.1);
+        assert_eq!(
+            *calls.lock().unwrap(),
+            vec![
+                (0, "laptop 56%".to_string(), 56),
+                (41, "laptop 100%".to_string(), 100),
+            ]
+        );
+    }
+}
@@ -0,0 +1,178 @@
+use log::{debug, warn};
+use std::{
+    fs::OpenOptions,
+    io::{self, Write},
+    os::unix::{fs::OpenOptionsExt, io::AsRawFd},
+    path::PathBuf,
+    process::{Child, Command, Stdio},
+};
+
+/// Where on-screen display feedback is written, one percentage per line as
+/// wob expects
+#[derive(Debug, PartialEq, Clone)]
+pub enum OsdTarget {
+    /// A FIFO that an OSD program such as wob reads from
+    Fifo(PathBuf),
+    /// A command run through sh, started on first use and kept running, that
+    /// reads percentages on stdin. Percentages it is too slow to take are
+    /// dropped.
+    Pipe(String),
+}
+
+/// Formatted as in the config file, e.g. "fifo:/run/user/1000/wob.sock"
+impl std::fmt::Display for OsdTarget {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            OsdTarget::Fifo(p) => write!(f, "fifo:{}", p.display()),
+            OsdTarget::Pipe(cmd) => write!(f, "pipe:{cmd}"),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Osd {
+    target: OsdTarget,
+    child: Option<Child>,
+}
+
+impl PartialEq for Osd {
+    fn eq(&self, other: &Self) -> bool {
+        self.target == other.target
+    }
+}
+
+impl Osd {
+    pub fn new(target: OsdTarget) -> Self {
+        Self {
+            target,
+            child: None,
+        }
+    }
+    /// Failures are only logged, feedback is not worth failing a command
+    pub fn show(&mut self, percent: f32) {
+        let percent = percent.clamp(0.0, 100.0).round() as u8;
+        if let Err(e) = self.write(percent) {
+            warn!("Could not write to OSD {}: {e}", self.target);
+        }
+    }
+    fn write(&mut self, percent: u8) -> io::Result<()> {
+        match self.target {
+            OsdTarget::Fifo(ref p) => {
+                // nonblocking, so that a FIFO without a reader fails at once
+                // rather than holding up every other command
+                let open = OpenOptions::new()
+                    .write(true)
+                    .custom_flags(libc::O_NONBLOCK)
+                    .open(p);
+                match open {
+                    Ok(mut f) => writeln!(f, "{percent}"),
+                    Err(e) if e.raw_os_error() == Some(libc::ENXIO) => {
+                        debug!("Nothing is reading OSD {}", self.target);
+                        Ok(())
+                    }
+                    Err(e) => Err(e),
+                }
+            }
+            OsdTarget::Pipe(_) => {
+                // a command that has exited is started again, once
+                if self.write_to_child(percent).is_err() {
+                    self.stop_child();
+                    self.write_to_child(percent)?;
+                }
+                Ok(())
+            }
+        }
+    }
+    fn write_to_child(&mut self, percent: u8) -> io::Result<()> {
+        let OsdTarget::Pipe(ref cmd) = self.target else {
+            return Ok(());
+        };
+        if self.child.is_none() {
+            debug!("Starting OSD command {cmd:?}");
+            let child = Command::new("/bin/sh")
+                .arg("-c")
+                .arg(cmd)
+                .stdin(Stdio::piped())
+                .stdout(Stdio::null())
+                .spawn()?;
+            // a command that stops reading must not hold up the daemon
+            if let Some(ref stdin) = child.stdin {
+                set_nonblocking(stdin)?;
+            }
+            self.child = Some(child);
+        }
+        let stdin = self.child.as_mut().and_then(|c| c.stdin.as_mut());
+        let Some(stdin) = stdin else {
+            return Err(io::ErrorKind::BrokenPipe.into());
+        };
+        // shorter than PIPE_BUF, so written whole or not at all
+        match stdin.write_all(format!("{percent}\n").as_bytes()) {
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                debug!("OSD command {cmd:?} is not reading, dropped {percent}");
+                Ok(())
+            }
+            r => r,
+        }
+    }
+    fn stop_child(&mut self) {
+        if let Some(mut child) = self.child.take() {
+            drop(child.stdin.take());
+            let _ = child.kill();
+            let _ = child.wait();
+        }
+    }
+}
+
+fn set_nonblocking(fd: &impl AsRawFd) -> io::Result<()> {
+    let fd = fd.as_raw_fd();
+    let flags = unsafe { libc::fcntl(fd, libc::F_GETFL) };
+    if flags < 0 || unsafe { libc::fcntl(fd, libc::F_SETFL, flags | libc::O_NONBLOCK) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+impl Drop for Osd {
+    fn drop(&mut self) {
+        self.stop_child();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{ffi::CString, fs, io::Read, os::unix::ffi::OsStrExt};
+
+    #[test]
+    fn test_fifo() {
+        let dir = std::env::temp_dir().join(format!("backlightd-osd-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("wob.sock");
+        let c_path = CString::new(path.as_os_str().as_bytes()).unwrap();
+        assert_eq!(unsafe { libc::mkfifo(c_path.as_ptr(), 0o600) }, 0);
+        let mut osd = Osd::new(OsdTarget::Fifo(path.clone()));
+        // no reader, must not block
+        osd.show(50.0);
+        let mut reader = OpenOptions::new()
+            .read(true)
+            .custom_flags(libc::O_NONBLOCK)
+            .open(&path)
+            .unwrap();
+        osd.show(66.7);
+        osd.show(120.0);
+        let mut s = String::new();
+        reader.read_to_string(&mut s).unwrap();
+        assert_eq!(s, "67\n100\n");
+        fs::remove_dir_all(dir).unwrap();
+    }
+    #[test]
+    fn test_pipe_that_stops_reading() {
+        let mut osd = Osd::new(OsdTarget::Pipe("exec sleep 10".into()));
+        let start = std::time::Instant::now();
+        // more than a pipe holds
+        for _ in 0..40000 {
+            osd.show(100.0);
+        }
+        assert!(start.elapsed() < std::time::Duration::from_secs(5));
+    }
+}
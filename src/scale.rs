@@ -9,6 +9,8 @@ pub struct ScaleBuilder {
     min_value: Option<usize>,
     ref_max: Option<usize>,
     ref_min: Option<usize>,
+    steps: Option<f32>,
+    default_level: Option<f32>,
 }
 
 impl ScaleBuilder {
@@ -35,14 +37,28 @@ impl ScaleBuilder {
         self.ref_min = Some(v);
         self
     }
+    /// Number of levels between ref_max and ref_min
+    pub fn steps(&mut self, v: f32) -> &mut Self {
+        self.steps = Some(v);
+        self
+    }
+    pub fn default_level(&mut self, v: f32) -> &mut Self {
+        self.default_level = Some(v);
+        self
+    }
     pub fn make(self) -> Result<BrightnessScale, Error> {
         let max_value = self.max_value.ok_or(Error::MaxBrightnessRequired)?;
         let min_value = self.min_value.unwrap_or(0);
         let ref_max = self.ref_max.map(|x| x as f32).unwrap_or(max_value as f32);
         let ref_min = self.ref_min.map(|x| x as f32).unwrap_or(min_value as f32);
+        let steps = self.steps.unwrap_or(STEPS_IN_REFERENCE_RANGE);
+        if steps.is_nan() || steps <= 0.0 {
+            return Err(Error::BadConfiguration("steps must be greater than zero"));
+        }
+        let default_level = self.default_level.unwrap_or(DEFAULT_LEVEL);
         // Assume linear scale if not specified
         let kind = self.kind.unwrap_or(ScaleKind::Linear);
-        let idx_factor = Self::idx_factor(&kind, ref_max, ref_min);
+        let idx_factor = Self::idx_factor(&kind, ref_max, ref_min, steps);
         Ok(BrightnessScale {
             kind,
             idx_factor,
@@ -50,23 +66,25 @@ impl ScaleBuilder {
             min_value,
             ref_max,
             ref_min,
-            level: DEFAULT_LEVEL,
+            steps,
+            default_level,
+            level: default_level,
         })
     }
-    fn idx_factor(kind: &ScaleKind, ref_max: f32, ref_min: f32) -> f32 {
+    fn idx_factor(kind: &ScaleKind, ref_max: f32, ref_min: f32, steps: f32) -> f32 {
         match kind {
-            ScaleKind::Linear => Self::linear_factor(ref_max, ref_min),
-            ScaleKind::Exp2(_) => Self::exp2_factor(ref_max, ref_min),
+            ScaleKind::Linear => Self::linear_factor(ref_max, ref_min, steps),
+            ScaleKind::Exp2(_) => Self::exp2_factor(ref_max, ref_min, steps),
         }
     }
-    fn linear_factor(ref_max: f32, ref_min: f32) -> f32 {
-        (ref_max - ref_min) / STEPS_IN_REFERENCE_RANGE
+    fn linear_factor(ref_max: f32, ref_min: f32, steps: f32) -> f32 {
+        (ref_max - ref_min) / steps
     }
-    fn exp2_factor(ref_max: f32, ref_min: f32) -> f32 {
+    fn exp2_factor(ref_max: f32, ref_min: f32, steps: f32) -> f32 {
         let ref_max_exp = f32::log2(ref_max);
         let ref_min_exp = f32::log2(ref_min);
         let stops = ref_max_exp - ref_min_exp;
-        stops / STEPS_IN_REFERENCE_RANGE
+        stops / steps
     }
 }
 
@@ -76,6 +94,27 @@ pub enum ScaleKind {
     Exp2(f32),
 }
 
+/// Progress of a fade over time
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum FadeCurve {
+    Linear,
+    /// Fast at first, slowing towards the target
+    EaseOut,
+    /// Slow at both ends
+    EaseInOut,
+}
+
+impl FadeCurve {
+    /// Fraction of the way at time t, both from 0 to 1
+    pub fn apply(self, t: f32) -> f32 {
+        match self {
+            FadeCurve::Linear => t,
+            FadeCurve::EaseOut => 1.0 - (1.0 - t) * (1.0 - t),
+            FadeCurve::EaseInOut => t * t * (3.0 - 2.0 * t),
+        }
+    }
+}
+
 #[derive(Debug, PartialEq)]
 pub struct BrightnessScale {
     kind: ScaleKind,
@@ -87,36 +126,259 @@ pub struct BrightnessScale {
     min_value: usize,
     ref_max: f32,
     ref_min: f32,
-    // current brightness level. 0-9 is the reference range.
-    level: i8,
+    // number of levels in the reference range
+    steps: f32,
+    default_level: f32,
+    // current brightness level. 0-steps is the reference range, fractional
+    // levels fall between the steps.
+    level: f32,
 }
 
 impl BrightnessScale {
-    pub fn value_for(&self, f: i8) -> ClampedValue<usize> {
-        let f = f as f32 * self.idx_factor;
-        let ref_max = self.ref_max as f32;
+    pub fn value_for(&self, f: f32) -> ClampedValue<usize> {
+        let f = f * self.idx_factor;
+        let ref_max = self.ref_max;
         let x = match self.kind {
             ScaleKind::Linear => ref_max - f,
             ScaleKind::Exp2(gamma) => ref_max / f32::powf(gamma, f),
         } as usize;
         ClampedValue::new(x, self.min_value, self.max_value)
     }
+    /// Inverse of value_for, without clamping. Not finite if the scale is
+    /// degenerate.
+    pub fn level_for_value(&self, x: f32) -> f32 {
+        let f = match self.kind {
+            ScaleKind::Linear => self.ref_max - x,
+            ScaleKind::Exp2(gamma) => f32::ln(self.ref_max / x) / f32::ln(gamma),
+        };
+        f / self.idx_factor
+    }
     pub fn get_brightness(&self) -> ClampedValue<usize> {
         self.value_for(self.level)
     }
+    pub fn level(&self) -> f32 {
+        self.level
+    }
+    pub fn steps(&self) -> f32 {
+        self.steps
+    }
+    pub fn default_level(&self) -> f32 {
+        self.default_level
+    }
     pub fn up(&mut self) -> ClampedValue<usize> {
-        self.level -= 1;
+        self.level -= 1.0;
         self.value_for(self.level)
     }
     pub fn down(&mut self) -> ClampedValue<usize> {
-        self.level += 1;
+        self.level += 1.0;
+        self.value_for(self.level)
+    }
+    /// Up by n levels, without passing the limit level. Never moves down.
+    pub fn up_by(&mut self, n: f32, limit: f32) -> ClampedValue<usize> {
+        let target = (self.level - n).max(limit);
+        self.level = self.level.min(target);
+        self.value_for(self.level)
+    }
+    /// Down by n levels, without passing the limit level. Never moves up.
+    pub fn down_by(&mut self, n: f32, limit: f32) -> ClampedValue<usize> {
+        let target = (self.level + n).min(limit);
+        self.level = self.level.max(target);
         self.value_for(self.level)
     }
-    pub fn set_level(&mut self, value: i8) -> ClampedValue<usize> {
+    /// Level for a percentage of the reference range, 100% being ref_max
+    pub fn level_for_percent(&self, percent: f32) -> f32 {
+        (100.0 - percent) / 100.0 * self.steps
+    }
+    /// Inverse of level_for_percent for the current level, rounded to a
+    /// tenth of a percent
+    pub fn percent(&self) -> f32 {
+        let percent = (self.steps - self.level) / self.steps * 100.0;
+        (percent * 10.0).round() / 10.0
+    }
+    pub fn set_level(&mut self, value: f32) -> ClampedValue<usize> {
         self.level = value;
         self.value_for(self.level)
     }
+    /// Moves to the level for a value read back from the display: the
+    /// nearest whole level if it gives that value, the exact level otherwise.
+    /// Unchanged if the current level already gives the value. Kept within
+    /// clamp_level, as a value outside min and max has no level of its own.
+    pub fn sync_to_value(&mut self, v: usize) {
+        if *self.get_brightness() == v {
+            return;
+        }
+        let exact = self.level_for_value(v as f32);
+        if !exact.is_finite() {
+            return;
+        }
+        let whole = exact.round();
+        let level = if *self.value_for(whole) == v {
+            whole
+        } else {
+            (exact * 100.0).round() / 100.0
+        };
+        self.level = self.clamp_level(level);
+    }
+    /// Values for a fade in frames steps from the value from to the current
+    /// level. Levels rather than values are interpolated, so that each step
+    /// looks the same size. The last value is always the current one.
+    pub fn fade_from(&self, from: usize, frames: usize, curve: FadeCurve) -> Vec<usize> {
+        let target = *self.get_brightness();
+        let start = self.level_for_value(from as f32);
+        if !start.is_finite() || frames < 2 {
+            return vec![target];
+        }
+        let mut values: Vec<usize> = (1..frames)
+            .map(|i| {
+                let t = curve.apply(i as f32 / frames as f32);
+                *self.value_for(start + (self.level - start) * t)
+            })
+            .collect();
+        values.push(target);
+        values
+    }
     pub fn set_to_default(&mut self) -> ClampedValue<usize> {
-        self.set_level(DEFAULT_LEVEL)
+        self.set_level(self.default_level)
+    }
+    /// Limits a level to the range between max_level and min_level, so that
+    /// the next step in either direction changes the value
+    pub fn clamp_level(&self, level: f32) -> f32 {
+        let (top, bottom) = (self.max_level(), self.min_level());
+        if top <= bottom {
+            level.clamp(top, bottom)
+        } else {
+            level
+        }
+    }
+    /// The whole level at which the brightness is clamped to max_value,
+    /// nearest to the intermediate range. One step down from here changes
+    /// the value.
+    pub fn max_level(&self) -> f32 {
+        let level = self.level_for_value(self.max_value as f32).floor();
+        if !level.is_finite() {
+            return self.level;
+        }
+        // rounding can leave the computed level just short of the clamp
+        if self.value_for(level).is_max() {
+            level
+        } else {
+            level - 1.0
+        }
+    }
+    /// The whole level at which the brightness is clamped to min_value,
+    /// nearest to the intermediate range. One step up from here changes the
+    /// value.
+    pub fn min_level(&self) -> f32 {
+        // values are truncated, so anything below min_value + 1 is clamped
+        let level = self.level_for_value(self.min_value as f32 + 1.0).ceil();
+        if !level.is_finite() {
+            return self.level;
+        }
+        if self.value_for(level).is_min() {
+            level
+        } else {
+            level + 1.0
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn exp2_scale() -> BrightnessScale {
+        let mut b = ScaleBuilder::new();
+        b.kind(ScaleKind::Exp2(2.2))
+            .max_value(120000)
+            .min_value(750)
+            .ref_max_value(90000);
+        b.make().unwrap()
+    }
+
+    #[test]
+    fn test_max_level() {
+        let mut s = exp2_scale();
+        let level = s.max_level();
+        assert!(s.set_level(level).is_max());
+        assert!(!s.down_by(1.0, f32::MAX).is_max());
+    }
+    #[test]
+    fn test_steps() {
+        let mut b = ScaleBuilder::new();
+        b.max_value(100).steps(20.0).default_level(10.0);
+        let s = b.make().unwrap();
+        assert_eq!(s.level(), 10.0);
+        assert_eq!(*s.get_brightness(), 50);
+        assert_eq!(s.level_for_percent(25.0), 15.0);
+        assert_eq!(s.min_level(), 20.0);
+        assert_eq!(s.clamp_level(30.0), 20.0);
+    }
+    #[test]
+    fn test_level_for_value() {
+        let s = exp2_scale();
+        let x = s.value_for(2.5);
+        let level = s.level_for_value(*x as f32);
+        assert!((level - 2.5).abs() < 0.01);
+    }
+    #[test]
+    fn test_up_by() {
+        let mut s = exp2_scale();
+        let limit = s.max_level();
+        s.set_level(4.0);
+        s.up_by(2.0, limit);
+        assert_eq!(s.level(), 2.0);
+        s.up_by(0.25, limit);
+        assert_eq!(s.level(), 1.75);
+        assert!(s.up_by(20.0, limit).is_max());
+        assert_eq!(s.level(), limit);
+    }
+    #[test]
+    fn test_level_for_percent() {
+        let s = exp2_scale();
+        assert_eq!(s.level_for_percent(100.0), 0.0);
+        assert_eq!(s.level_for_percent(0.0), 9.0);
+        assert_eq!(s.level_for_percent(50.0), 4.5);
+        let mut s = s;
+        s.set_level(3.0);
+        assert_eq!(s.percent(), 66.7);
+    }
+    #[test]
+    fn test_fade_from() {
+        let mut s = exp2_scale();
+        s.set_level(0.0);
+        let values = s.fade_from(*s.value_for(4.0), 4, FadeCurve::Linear);
+        let expected: Vec<usize> = [3.0, 2.0, 1.0, 0.0].map(|l| *s.value_for(l)).to_vec();
+        // the start level is recovered from a truncated value
+        for (v, e) in values.iter().zip(&expected) {
+            assert!(v.abs_diff(*e) <= 1, "{values:?} != {expected:?}");
+        }
+        let values = s.fade_from(*s.value_for(4.0), 4, FadeCurve::EaseOut);
+        assert!(values[0] > expected[0]);
+        assert_eq!(values[3], 90000);
+        assert_eq!(s.fade_from(0, 4, FadeCurve::Linear), vec![90000]);
+    }
+    #[test]
+    fn test_sync_to_value() {
+        let mut b = ScaleBuilder::new();
+        b.max_value(100);
+        let mut s = b.make().unwrap();
+        s.sync_to_value(66);
+        assert_eq!(s.level(), 3.0);
+        s.sync_to_value(50);
+        assert_eq!(s.level(), 4.5);
+        // already there, keep the level rather than jump to an equivalent one
+        s.set_level(4.52);
+        s.sync_to_value(49);
+        assert_eq!(s.level(), 4.52);
+        // beyond max, as when the monitor's range differs from the config
+        s.sync_to_value(150);
+        assert_eq!(s.level(), s.max_level());
+    }
+    #[test]
+    fn test_min_level() {
+        let mut s = exp2_scale();
+        let level = s.min_level();
+        assert!(s.set_level(level).is_min());
+        assert!(!s.up_by(1.0, f32::MIN).is_min());
     }
 }
@@ -0,0 +1,182 @@
+use crate::error::Error;
+use log::warn;
+use std::{
+    collections::HashMap,
+    ffi::OsString,
+    fs::{self, File},
+    io::Write,
+    path::{Path, PathBuf},
+    process,
+    sync::mpsc::{self, RecvTimeoutError},
+    thread,
+    time::Duration,
+};
+use toml::{Table, Value};
+
+/// How long levels have to stay unchanged before they are saved, so that a
+/// run of changes is written once
+const SAVE_DELAY: Duration = Duration::from_secs(1);
+
+/// Brightness levels keyed by display name
+pub type Levels = HashMap<OsString, f32>;
+
+/// $XDG_STATE_HOME/backlightd/state, or ~/.local/state/backlightd/state
+pub fn state_file_path() -> Option<PathBuf> {
+    let mut p = match std::env::var_os("XDG_STATE_HOME") {
+        Some(xdg_state_home) => PathBuf::from(xdg_state_home),
+        None => {
+            let mut home = PathBuf::from(std::env::var_os("HOME")?);
+            home.push(".local");
+            home.push("state");
+            home
+        }
+    };
+    p.push("backlightd");
+    p.push("state");
+    Some(p)
+}
+
+/// The state file is a toml document with a [levels] table
+pub fn read_levels(path: &Path) -> Result<Levels, Error> {
+    let contents = fs::read_to_string(path)?;
+    let doc = contents
+        .parse::<Table>()
+        .map_err(|_| Error::BadConfiguration("Could not parse the state file"))?;
+    let mut levels = Levels::new();
+    if let Some(table) = doc.get("levels").and_then(|v| v.as_table()) {
+        for (name, v) in table {
+            let level = match (v.as_float(), v.as_integer()) {
+                (Some(x), _) => x as f32,
+                (_, Some(x)) => x as f32,
+                _ => continue,
+            };
+            levels.insert(name.into(), level);
+        }
+    }
+    Ok(levels)
+}
+
+/// The temporary file is named after the process, so that daemons sharing a
+/// state file cannot write into each other's
+fn temp_path(path: &Path) -> PathBuf {
+    let mut name = OsString::from(".");
+    name.push(path.file_name().unwrap_or_default());
+    name.push(format!(".{}.tmp", process::id()));
+    path.with_file_name(name)
+}
+
+/// Writes to a temporary file first and renames it over the old state, so a
+/// crash leaves either the old or the new file in place
+pub fn write_levels(path: &Path, levels: &Levels) -> Result<(), Error> {
+    let mut table = Table::new();
+    for (name, level) in levels {
+        let key = name.to_string_lossy().into_owned();
+        table.insert(key, Value::Float(*level as f64));
+    }
+    let mut doc = Table::new();
+    doc.insert("levels".into(), Value::Table(table));
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let tmp = temp_path(path);
+    let mut f = File::create(&tmp)?;
+    f.write_all(doc.to_string().as_bytes())?;
+    f.sync_all()?;
+    fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+/// Remembers what was last written, so that unchanged levels are not
+/// rewritten after every command
+pub struct LevelStore {
+    path: PathBuf,
+    saved: Levels,
+}
+
+impl LevelStore {
+    pub fn new(path: PathBuf) -> Self {
+        Self {
+            path,
+            saved: Levels::new(),
+        }
+    }
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+    pub fn load(&mut self) -> Result<Levels, Error> {
+        self.saved = read_levels(&self.path)?;
+        Ok(self.saved.clone())
+    }
+    pub fn save(&mut self, levels: Levels) -> Result<(), Error> {
+        if levels != self.saved {
+            write_levels(&self.path, &levels)?;
+            self.saved = levels;
+        }
+        Ok(())
+    }
+    /// Saves the levels sent to the returned sender on a thread of its own,
+    /// so that commands do not wait for the disk
+    pub fn spawn_saver(self) -> mpsc::Sender<Levels> {
+        let (sender, incoming) = mpsc::channel();
+        thread::spawn(move || save_levels(self, incoming));
+        sender
+    }
+}
+
+/// Saves the newest levels once none have come for SAVE_DELAY, and any
+/// unsaved ones when the sender is dropped
+fn save_levels(mut store: LevelStore, incoming: mpsc::Receiver<Levels>) {
+    while let Ok(mut levels) = incoming.recv() {
+        let disconnected = loop {
+            match incoming.recv_timeout(SAVE_DELAY) {
+                Ok(newer) => levels = newer,
+                Err(RecvTimeoutError::Timeout) => break false,
+                Err(RecvTimeoutError::Disconnected) => break true,
+            }
+        };
+        if let Err(e) = store.save(levels) {
+            warn!("Could not save brightness levels to {:?}: {e}", store.path());
+        }
+        if disconnected {
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_levels_round_trip() {
+        let dir = std::env::temp_dir().join(format!("backlightd-test-{}", std::process::id()));
+        let path = dir.join("state");
+        let mut levels = Levels::new();
+        levels.insert("laptop".into(), 4.5);
+        levels.insert("DP 3".into(), -1.0);
+        write_levels(&path, &levels).unwrap();
+        assert_eq!(read_levels(&path).unwrap(), levels);
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+        fs::remove_dir_all(dir).unwrap();
+    }
+    #[test]
+    fn test_only_the_newest_levels_are_saved() {
+        let dir = std::env::temp_dir().join(format!("backlightd-saver-{}", process::id()));
+        let path = dir.join("state");
+        let (sender, incoming) = mpsc::channel();
+        for level in [1.0, 2.0, 3.0] {
+            sender.send(Levels::from([("laptop".into(), level)])).unwrap();
+        }
+        drop(sender);
+        let mut store = LevelStore::new(path.clone());
+        store.saved = Levels::from([("laptop".into(), 3.0)]);
+        save_levels(store, incoming);
+        assert!(!path.exists());
+        let (sender, incoming) = mpsc::channel();
+        sender.send(Levels::from([("laptop".into(), 2.0)])).unwrap();
+        drop(sender);
+        save_levels(LevelStore::new(path.clone()), incoming);
+        assert_eq!(read_levels(&path).unwrap(), Levels::from([("laptop".into(), 2.0)]));
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
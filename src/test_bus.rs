@@ -0,0 +1,51 @@
+use std::{
+    env,
+    io::{BufRead, BufReader},
+    process::{Child, Command, Stdio},
+};
+
+/// Set to let the tests that need dbus-daemon skip where it is not installed
+const SKIP_VAR: &str = "BACKLIGHTD_SKIP_DBUS_TESTS";
+
+/// A private bus for tests of the D-Bus clients, stopped when dropped
+pub struct TestBus {
+    daemon: Child,
+    address: String,
+}
+
+impl TestBus {
+    /// Starts dbus-daemon. If it cannot be started, the test called name is
+    /// skipped with a note when BACKLIGHTD_SKIP_DBUS_TESTS is set, and fails
+    /// otherwise, so that it never passes without having run.
+    pub fn start(name: &str) -> Option<Self> {
+        let daemon = Command::new("dbus-daemon")
+            .args(["--session", "--nofork", "--print-address"])
+            .stdout(Stdio::piped())
+            .spawn();
+        let mut daemon = match daemon {
+            Ok(daemon) => daemon,
+            Err(e) if env::var_os(SKIP_VAR).is_some() => {
+                eprintln!("skipping {name}: could not start dbus-daemon: {e}");
+                return None;
+            }
+            Err(e) => panic!("could not start dbus-daemon: {e}, set {SKIP_VAR} to skip"),
+        };
+        let mut address = String::new();
+        let stdout = daemon.stdout.take().unwrap();
+        BufReader::new(stdout).read_line(&mut address).unwrap();
+        Some(Self {
+            daemon,
+            address: address.trim().into(),
+        })
+    }
+    pub fn address(&self) -> &str {
+        &self.address
+    }
+}
+
+impl Drop for TestBus {
+    fn drop(&mut self) {
+        let _ = self.daemon.kill();
+        let _ = self.daemon.wait();
+    }
+}
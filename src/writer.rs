@@ -0,0 +1,300 @@
+use crate::scale::FadeCurve;
+use log::{debug, error};
+use std::{
+    collections::VecDeque,
+    io,
+    sync::{Arc, Condvar, Mutex},
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+/// Shortest time between the steps of a fade
+const FADE_FRAME: Duration = Duration::from_millis(20);
+
+/// A gradual change of brightness, rather than a jump
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct Fade {
+    pub duration: Duration,
+    pub curve: FadeCurve,
+}
+
+/// Writes brightness values on a thread of its own, so that a slow display
+/// holds up neither the daemon nor the other displays. Only the latest value
+/// is written: values that arrive while a write is in progress, or while the
+/// interval since the last write runs out, replace each other.
+pub struct Writer {
+    shared: Arc<Shared>,
+    interval: Duration,
+    thread: Option<JoinHandle<()>>,
+}
+
+#[derive(Default)]
+struct Shared {
+    state: Mutex<State>,
+    changed: Condvar,
+}
+
+#[derive(Default)]
+struct State {
+    /// Values still to be written, more than one during a fade
+    pending: VecDeque<usize>,
+    /// Time between the steps of a fade
+    frame: Duration,
+    /// The value the display has now, as far as we know
+    written: Option<usize>,
+    /// Counts the calls that queued values
+    queued: u64,
+    /// The count when the values of the last write were queued
+    attempted: u64,
+    /// Why the last write of the latest values failed, until it is reported
+    error: Option<io::Error>,
+    busy: bool,
+    closed: bool,
+}
+
+impl Writer {
+    /// write is called on the worker thread with each value that is not
+    /// superseded, at least interval apart. Failures are logged with label,
+    /// and reported by check or wait until newer values are queued.
+    pub fn new<F>(label: String, interval: Duration, write: F) -> Self
+    where
+        F: FnMut(usize) -> io::Result<()> + Send + 'static,
+    {
+        let shared = Arc::new(Shared::default());
+        let worker = shared.clone();
+        let thread = thread::spawn(move || run(&worker, &label, interval, write));
+        Self {
+            shared,
+            interval,
+            thread: Some(thread),
+        }
+    }
+    /// Queues v, replacing any value that has not been written yet
+    pub fn set(&self, v: usize) {
+        self.replace(VecDeque::from([v]), Duration::ZERO)
+    }
+    /// Queues values to be written a frame apart, replacing any that have
+    /// not been written yet. A fade in progress ends where it is.
+    pub fn fade(&self, values: Vec<usize>, frame: Duration) {
+        self.replace(values.into(), frame)
+    }
+    fn replace(&self, values: VecDeque<usize>, frame: Duration) {
+        let mut state = self.shared.state.lock().unwrap();
+        if !state.pending.is_empty() {
+            debug!("Dropped {} superseded brightness values", state.pending.len());
+        }
+        state.pending = values;
+        state.frame = frame;
+        state.queued += 1;
+        // a failure of older values is not theirs to report
+        state.error = None;
+        self.shared.changed.notify_all();
+    }
+    /// The last value written, None before the first write
+    pub fn written(&self) -> Option<usize> {
+        self.shared.state.lock().unwrap().written
+    }
+    /// Number of steps for a fade that takes duration
+    pub fn frames(&self, duration: Duration) -> usize {
+        let frame = self.frame_interval().as_millis();
+        (duration.as_millis() / frame).max(1) as usize
+    }
+    /// Time between the steps of a fade
+    pub fn frame_interval(&self) -> Duration {
+        self.interval.max(FADE_FRAME)
+    }
+    /// Waits up to timeout for the first write of the latest values, and
+    /// returns its error if it failed. Ok if it has not been made in time.
+    pub fn check(&self, timeout: Duration) -> io::Result<()> {
+        let state = self.shared.state.lock().unwrap();
+        let (mut state, _) = self
+            .shared
+            .changed
+            .wait_timeout_while(state, timeout, |s| s.attempted < s.queued)
+            .unwrap();
+        state.error.take().map_or(Ok(()), Err)
+    }
+    /// Blocks until every queued value has been written, and returns the
+    /// error of the last write if it failed
+    pub fn wait(&self) -> io::Result<()> {
+        let state = self.shared.state.lock().unwrap();
+        let mut state = self
+            .shared
+            .changed
+            .wait_while(state, |s| !s.pending.is_empty() || s.busy)
+            .unwrap();
+        state.error.take().map_or(Ok(()), Err)
+    }
+}
+
+fn run<F>(shared: &Shared, label: &str, interval: Duration, mut write: F)
+where
+    F: FnMut(usize) -> io::Result<()>,
+{
+    let mut last_write: Option<Instant> = None;
+    loop {
+        let state = shared.state.lock().unwrap();
+        let state = shared
+            .changed
+            .wait_while(state, |s| s.pending.is_empty() && !s.closed)
+            .unwrap();
+        if state.pending.is_empty() {
+            // closed, and nothing left to write
+            return;
+        }
+        let gap = interval.max(state.frame);
+        drop(state);
+        // newer values keep arriving while we wait, only the last is written
+        if let Some(elapsed) = last_write.map(|t| t.elapsed()) {
+            if elapsed < gap {
+                thread::sleep(gap - elapsed);
+            }
+        }
+        let mut state = shared.state.lock().unwrap();
+        let Some(v) = state.pending.pop_front() else {
+            continue;
+        };
+        let queued = state.queued;
+        state.busy = true;
+        drop(state);
+        let r = write(v);
+        last_write = Some(Instant::now());
+        let mut state = shared.state.lock().unwrap();
+        state.attempted = queued;
+        // the outcome only stands for values that have not been replaced
+        let latest = queued == state.queued;
+        match r {
+            Ok(()) => {
+                state.written = Some(v);
+                if latest {
+                    state.error = None;
+                }
+            }
+            Err(e) => {
+                error!("Could not set brightness on {label}: {e}");
+                if latest {
+                    state.error = Some(e);
+                }
+            }
+        }
+        state.busy = false;
+        drop(state);
+        shared.changed.notify_all();
+    }
+}
+
+/// The pending value is still written before the thread exits
+impl Drop for Writer {
+    fn drop(&mut self) {
+        self.shared.state.lock().unwrap().closed = true;
+        self.shared.changed.notify_all();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+impl std::fmt::Debug for Writer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Writer")
+            .field("interval", &self.interval)
+            .finish_non_exhaustive()
+    }
+}
+
+impl PartialEq for Writer {
+    fn eq(&self, other: &Self) -> bool {
+        self.interval == other.interval
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Values written, and when
+    type Writes = Arc<Mutex<Vec<(usize, Instant)>>>;
+
+    fn recording_writer(interval: Duration, delay: Duration) -> (Writer, Writes) {
+        let writes = Arc::new(Mutex::new(Vec::new()));
+        let w = writes.clone();
+        let writer = Writer::new("test".into(), interval, move |v| {
+            thread::sleep(delay);
+            w.lock().unwrap().push((v, Instant::now()));
+            Ok(())
+        });
+        (writer, writes)
+    }
+
+    #[test]
+    fn test_superseded_values_are_dropped() {
+        let (writer, writes) = recording_writer(Duration::ZERO, Duration::from_millis(50));
+        for v in 1..=10 {
+            writer.set(v);
+        }
+        writer.wait().unwrap();
+        let values: Vec<usize> = writes.lock().unwrap().iter().map(|w| w.0).collect();
+        // one value may have been taken before the rest arrived
+        assert!(values.len() <= 2, "{values:?}");
+        assert_eq!(values.last(), Some(&10));
+    }
+    #[test]
+    fn test_fade_is_retargeted() {
+        let (writer, writes) = recording_writer(Duration::ZERO, Duration::ZERO);
+        let frame = Duration::from_millis(30);
+        writer.fade(vec![10, 20, 30, 40, 50], frame);
+        thread::sleep(frame * 2);
+        writer.fade(vec![5], frame);
+        writer.wait().unwrap();
+        let values: Vec<usize> = writes.lock().unwrap().iter().map(|w| w.0).collect();
+        assert_eq!(values.last(), Some(&5));
+        assert!(!values.contains(&50), "{values:?}");
+        assert_eq!(writer.written(), Some(5));
+    }
+    #[test]
+    fn test_interval() {
+        let interval = Duration::from_millis(100);
+        let (writer, writes) = recording_writer(interval, Duration::ZERO);
+        writer.set(1);
+        writer.wait().unwrap();
+        writer.set(2);
+        // dropping writes what is still pending
+        drop(writer);
+        let writes = writes.lock().unwrap();
+        assert_eq!(writes.len(), 2);
+        assert!(writes[1].1 - writes[0].1 >= interval);
+    }
+    #[test]
+    fn test_failed_write_is_reported() {
+        let writer = Writer::new("test".into(), Duration::ZERO, |v| match v {
+            0 => Err(io::Error::other("gone")),
+            _ => Ok(()),
+        });
+        writer.set(1);
+        writer.wait().unwrap();
+        writer.set(0);
+        assert!(writer.wait().is_err());
+        assert_eq!(writer.written(), Some(1));
+        // reported once
+        writer.check(Duration::ZERO).unwrap();
+        writer.set(2);
+        writer.wait().unwrap();
+        assert_eq!(writer.written(), Some(2));
+    }
+    #[test]
+    fn test_failure_is_reported_for_its_own_values() {
+        let writer = Writer::new("test".into(), Duration::ZERO, |v| match v {
+            0 => Err(io::Error::other("gone")),
+            _ => Ok(()),
+        });
+        writer.set(0);
+        assert!(writer.check(Duration::from_secs(1)).is_err());
+        writer.set(0);
+        thread::sleep(Duration::from_millis(50));
+        // not the next values' failure
+        writer.set(3);
+        writer.check(Duration::from_secs(1)).unwrap();
+        writer.wait().unwrap();
+        assert_eq!(writer.written(), Some(3));
+    }
+}